@@ -0,0 +1,47 @@
+//! Round trips of a single fd, through the stateless trait methods with and
+//! without payload, and through `FdChannel`.
+//!
+//! Run with `cargo bench --bench channel`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use passfd::{FdChannel, FdPassingExt};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+
+fn round_trip(c: &mut Criterion) {
+    let file = File::open("/etc/passwd").unwrap();
+    let fd = file.as_raw_fd();
+
+    let (tx, rx) = UnixStream::pair().unwrap();
+    c.bench_function("send_fd", |b| {
+        b.iter(|| {
+            tx.send_fd(fd).unwrap();
+            drop(unsafe { OwnedFd::from_raw_fd(rx.recv_fd().unwrap()) });
+        })
+    });
+
+    let (tx, rx) = UnixStream::pair().unwrap();
+    let payload = [0xa5; 64];
+    let mut buf = [0u8; 64];
+    c.bench_function("payload", |b| {
+        b.iter(|| {
+            tx.send_fd_with_payload(fd, &payload).unwrap();
+            let (fd, len) = rx.recv_fd_with_payload(&mut buf).unwrap();
+            assert_eq!(len, payload.len());
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        })
+    });
+
+    let (tx, rx) = UnixStream::pair().unwrap();
+    let (mut tx, mut rx) = (FdChannel::new(tx), FdChannel::new(rx));
+    c.bench_function("FdChannel", |b| {
+        b.iter(|| {
+            tx.send(fd).unwrap();
+            drop(rx.recv().unwrap());
+        })
+    });
+}
+
+criterion_group!(benches, round_trip);
+criterion_main!(benches);
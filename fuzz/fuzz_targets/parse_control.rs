@@ -0,0 +1,23 @@
+//! Feed arbitrary control buffers to `parse_control`.
+//!
+//! Run with `cargo fuzz run parse_control` from the crate root.
+
+#![no_main]
+
+use std::mem;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the kernel hands out buffers aligned for cmsghdr, so does this
+    let zeroed: libc::cmsghdr = unsafe { mem::zeroed() };
+    let mut control = vec![zeroed; data.len().div_ceil(mem::size_of::<libc::cmsghdr>())];
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), control.as_mut_ptr() as *mut u8, data.len());
+    }
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = data.len() as _;
+    // only parses, the made up fds are never owned or closed
+    let _ = unsafe { passfd::parse_control(&msg) };
+});
@@ -0,0 +1,79 @@
+//! Conversions between `SocketAddr` and `sockaddr_un`, for `msg_name`.
+
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::SocketAddr;
+
+#[cfg(target_os = "android")]
+use std::os::android::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+/// Raw Unix socket address together with its length
+pub(crate) struct UnixAddr {
+    pub(crate) addr: libc::sockaddr_un,
+    pub(crate) len: libc::socklen_t,
+}
+
+// sun_path is the last field, so everything before it is the header
+fn path_offset() -> usize {
+    let addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    mem::size_of_val(&addr) - mem::size_of_val(&addr.sun_path)
+}
+
+impl UnixAddr {
+    /// Empty buffer for the kernel to fill in
+    pub(crate) fn new() -> UnixAddr {
+        UnixAddr {
+            addr: unsafe { mem::zeroed() },
+            len: mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        }
+    }
+
+    pub(crate) fn from_socket_addr(addr: &SocketAddr) -> Result<UnixAddr, Error> {
+        let mut raw = UnixAddr::new();
+        raw.addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let (start, name) = if let Some(path) = addr.as_pathname() {
+            (0, path.as_os_str().as_bytes())
+        } else {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let name = addr.as_abstract_name().map(|name| (1, name));
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let name = None;
+            name.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "unnamed socket address"))?
+        };
+        // pathnames are NUL terminated
+        let terminator = if start == 0 { 1 } else { 0 };
+        if start + name.len() + terminator > raw.addr.sun_path.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "socket address too long",
+            ));
+        }
+        for (dst, src) in raw.addr.sun_path[start..].iter_mut().zip(name) {
+            *dst = *src as libc::c_char;
+        }
+        raw.len = (path_offset() + start + name.len() + terminator) as libc::socklen_t;
+        Ok(raw)
+    }
+
+    pub(crate) fn to_socket_addr(&self) -> Result<SocketAddr, Error> {
+        let len = (self.len as usize)
+            .saturating_sub(path_offset())
+            .min(self.addr.sun_path.len());
+        let path: Vec<u8> = self.addr.sun_path[..len].iter().map(|c| *c as u8).collect();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some((0, name)) = path.split_first() {
+            return SocketAddr::from_abstract_name(name);
+        }
+        let path = match path.iter().position(|c| *c == 0) {
+            Some(end) => &path[..end],
+            None => &path[..],
+        };
+        // an empty pathname is how std builds an unnamed address, which is
+        // what an unbound or socketpair peer has (namelen 0 or just the family)
+        SocketAddr::from_pathname(OsStr::from_bytes(path))
+    }
+}
@@ -0,0 +1,107 @@
+//! Support for async-io `Async<UnixStream>`, as used by smol.
+
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use ::async_io::Async;
+use futures_core::ready;
+
+use crate::FdPassingExt as SyncFdPassingExt;
+
+/// Main trait, extends `Async<UnixStream>`
+pub trait FdPassingExt {
+    /// Send RawFd. No type information is transmitted.
+    fn send_fd(&self, fd: RawFd) -> SendFd<'_>;
+    /// Receive RawFd. No type information is transmitted.
+    fn recv_fd(&self) -> RecvFd<'_>;
+}
+
+pub struct SendFd<'a> {
+    stream: &'a Async<UnixStream>,
+    fd: RawFd,
+}
+
+impl<'a> Future for SendFd<'a> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+        let stream_fd = this.stream.get_ref().as_raw_fd();
+
+        loop {
+            match stream_fd.send_fd_nonblocking(this.fd) {
+                Ok(_) => break Poll::Ready(Ok(())),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => break Poll::Ready(Err(err)),
+            }
+            ready!(this.stream.poll_writable(cx))?;
+        }
+    }
+}
+
+pub struct RecvFd<'a> {
+    stream: &'a Async<UnixStream>,
+}
+
+impl<'a> Future for RecvFd<'a> {
+    type Output = Result<RawFd, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+        let stream_fd = this.stream.get_ref().as_raw_fd();
+
+        loop {
+            match stream_fd.recv_fd_nonblocking() {
+                Ok(val) => break Poll::Ready(Ok(val)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => break Poll::Ready(Err(err)),
+            }
+            ready!(this.stream.poll_readable(cx))?;
+        }
+    }
+}
+
+impl FdPassingExt for Async<UnixStream> {
+    fn send_fd(&self, fd: RawFd) -> SendFd<'_> {
+        SendFd { stream: self, fd }
+    }
+
+    fn recv_fd(&self) -> RecvFd<'_> {
+        RecvFd { stream: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdPassingExt;
+    use async_io::{block_on, Async, Timer};
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn async_io_works() {
+        let (tx, rx) = Async::<UnixStream>::pair().unwrap();
+        let sender = std::thread::spawn(move || {
+            block_on(async {
+                Timer::after(Duration::from_millis(50)).await;
+                let file = File::open("/etc/passwd").unwrap();
+                tx.send_fd(file.as_raw_fd()).await.unwrap();
+            })
+        });
+        // receiver starts first, so it has to wait for readiness
+        let fd = block_on(rx.recv_fd()).unwrap();
+        sender.join().unwrap();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Support for async-std UnixStream.
+//! async-std doesn't expose readiness of its sockets, so it's tracked on a
+//! duplicate of the stream's descriptor registered with async-io.
+
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use ::async_io::Async;
+use ::async_std::os::unix::net::UnixStream;
+use futures_core::ready;
+
+use crate::FdPassingExt as SyncFdPassingExt;
+
+/// Main trait, extends UnixStream
+pub trait FdPassingExt {
+    /// Send RawFd. No type information is transmitted.
+    fn send_fd(&self, fd: RawFd) -> SendFd<'_>;
+    /// Receive RawFd. No type information is transmitted.
+    fn recv_fd(&self) -> RecvFd<'_>;
+}
+
+// Registered lazily, only once the socket isn't ready right away
+fn watcher(stream: &UnixStream) -> Result<Async<StdUnixStream>, Error> {
+    // stream is alive for the duration of the borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) }.try_clone_to_owned()?;
+    Async::new(StdUnixStream::from(fd))
+}
+
+pub struct SendFd<'a> {
+    stream: &'a UnixStream,
+    fd: RawFd,
+    watcher: Option<Async<StdUnixStream>>,
+}
+
+impl<'a> Future for SendFd<'a> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+        let stream_fd = this.stream.as_raw_fd();
+
+        loop {
+            match stream_fd.send_fd_nonblocking(this.fd) {
+                Ok(_) => break Poll::Ready(Ok(())),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => break Poll::Ready(Err(err)),
+            }
+            if this.watcher.is_none() {
+                this.watcher = Some(watcher(this.stream)?);
+            }
+            ready!(this.watcher.as_ref().unwrap().poll_writable(cx))?;
+        }
+    }
+}
+
+pub struct RecvFd<'a> {
+    stream: &'a UnixStream,
+    watcher: Option<Async<StdUnixStream>>,
+}
+
+impl<'a> Future for RecvFd<'a> {
+    type Output = Result<RawFd, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+        let stream_fd = this.stream.as_raw_fd();
+
+        loop {
+            match stream_fd.recv_fd_nonblocking() {
+                Ok(val) => break Poll::Ready(Ok(val)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => break Poll::Ready(Err(err)),
+            }
+            if this.watcher.is_none() {
+                this.watcher = Some(watcher(this.stream)?);
+            }
+            ready!(this.watcher.as_ref().unwrap().poll_readable(cx))?;
+        }
+    }
+}
+
+impl FdPassingExt for UnixStream {
+    fn send_fd(&self, fd: RawFd) -> SendFd<'_> {
+        SendFd {
+            stream: self,
+            fd,
+            watcher: None,
+        }
+    }
+
+    fn recv_fd(&self) -> RecvFd<'_> {
+        RecvFd {
+            stream: self,
+            watcher: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdPassingExt;
+    use async_std::os::unix::net::UnixStream;
+    use async_std::task;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    #[test]
+    fn async_std_works() {
+        task::block_on(async {
+            let (tx, rx) = UnixStream::pair()?;
+            // receiver starts first, so it has to wait for readiness
+            let receiver = task::spawn(async move {
+                let fd = rx.recv_fd().await?;
+                let mut file = unsafe { File::from_raw_fd(fd) };
+                let mut buf = String::new();
+                file.read_to_string(&mut buf)?;
+                Ok::<_, std::io::Error>(buf)
+            });
+            task::sleep(std::time::Duration::from_millis(50)).await;
+            let file = File::open("/etc/passwd")?;
+            tx.send_fd(file.as_raw_fd()).await?;
+            assert!(!receiver.await?.is_empty());
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+    }
+}
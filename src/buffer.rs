@@ -0,0 +1,96 @@
+//! Reusable control buffer for allocation-free receiving.
+
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+
+use crate::MAX_FDS_PER_MESSAGE;
+
+/// Control buffer aligned for `cmsghdr`, see `aligned_control_buffer`.
+pub struct AlignedBuffer {
+    hdrs: Vec<libc::cmsghdr>,
+    len: usize,
+}
+
+/// Control buffer with room for `N` bytes, aligned for `cmsghdr`, that can
+/// live on the stack. Size it with `control_buffer_size`:
+///
+/// ```
+/// use passfd::{control_buffer_size, AlignedCmsgBuf};
+///
+/// let mut buf = AlignedCmsgBuf::<{ control_buffer_size(1) }>::new();
+/// assert_eq!(buf.len(), control_buffer_size(1));
+/// ```
+#[repr(C)]
+pub struct AlignedCmsgBuf<const N: usize> {
+    _align: [libc::cmsghdr; 0],
+    bytes: [u8; N],
+}
+
+impl<const N: usize> AlignedCmsgBuf<N> {
+    pub const fn new() -> Self {
+        AlignedCmsgBuf {
+            _align: [],
+            bytes: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> Default for AlignedCmsgBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for AlignedCmsgBuf<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<const N: usize> DerefMut for AlignedCmsgBuf<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+/// Bytes of control buffer needed to receive `n_fds` descriptors, i.e.
+/// `CMSG_SPACE` for that many ints. Usable in constants and array sizes.
+/// `n_fds` is capped at `MAX_FDS_PER_MESSAGE`.
+pub const fn control_buffer_size(n_fds: usize) -> usize {
+    let n_fds = if n_fds > MAX_FDS_PER_MESSAGE {
+        MAX_FDS_PER_MESSAGE
+    } else {
+        n_fds
+    };
+    unsafe { libc::CMSG_SPACE((mem::size_of::<libc::c_int>() * n_fds) as u32) as usize }
+}
+
+/// Allocate a control buffer big enough for `fds` descriptors.
+///
+/// Meant to be allocated once and reused with `recv_fd_with_buffer`, which
+/// then doesn't allocate at all. `fds` is capped at `MAX_FDS_PER_MESSAGE`.
+pub fn aligned_control_buffer(fds: usize) -> AlignedBuffer {
+    let len = control_buffer_size(fds);
+    let zeroed: libc::cmsghdr = unsafe { mem::zeroed() };
+    AlignedBuffer {
+        hdrs: vec![zeroed; len.div_ceil(mem::size_of::<libc::cmsghdr>())],
+        len,
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.hdrs.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.hdrs.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
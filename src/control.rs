@@ -0,0 +1,101 @@
+//! Parsing of received control messages, kept free of syscalls so it can
+//! be fuzzed on its own.
+
+use std::mem;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+
+use libc::{c_int, msghdr};
+
+use crate::raw::{data_room, headers, rights, rights_count};
+use crate::PassFdError;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::UCred;
+
+/// What a control buffer carries, as found by `parse_control`.
+///
+/// Descriptors are left where they are, nothing owns them yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedControl {
+    /// Number of descriptors in all SCM_RIGHTS headers
+    pub fd_count: usize,
+    /// First of them
+    pub first_fd: Option<RawFd>,
+    /// Some SCM_RIGHTS header carried no descriptors at all
+    pub empty_rights: bool,
+    /// Some header was something else than SCM_RIGHTS
+    pub other: bool,
+    /// Contents of a SCM_CREDENTIALS header
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub creds: Option<UCred>,
+}
+
+/// Walk the control buffer of `msg` and check each header's length.
+///
+/// Doesn't make any syscalls. Fails with `PassFdError::BadCmsgLen` if a
+/// header is too short for what it claims to carry.
+///
+/// # Safety
+///
+/// `msg.msg_control` must point to `msg.msg_controllen` readable bytes,
+/// aligned for `cmsghdr`, or be null.
+pub unsafe fn parse_control(msg: &msghdr) -> Result<ParsedControl, PassFdError> {
+    let mut parsed = ParsedControl::default();
+    let mut short = false;
+    for hdr in headers(msg) {
+        // msg_controllen may or may not include trailing padding, so only
+        // the header's own length is checked
+        #[allow(clippy::unnecessary_cast)]
+        let len = (*hdr).cmsg_len as usize;
+        let header = libc::CMSG_LEN(0) as usize;
+        if (*hdr).cmsg_level != libc::SOL_SOCKET {
+            parsed.other = true;
+            continue;
+        }
+        // cmsg_len can claim more than the buffer holds
+        if len.saturating_sub(header) > data_room(msg, hdr) {
+            short = true;
+        }
+        match (*hdr).cmsg_type {
+            libc::SCM_RIGHTS => {
+                if len == header {
+                    parsed.empty_rights = true;
+                } else if len < header || (len - header) % mem::size_of::<c_int>() != 0 {
+                    short = true;
+                }
+                let count = rights_count(msg, hdr);
+                if count > 0 && parsed.first_fd.is_none() {
+                    let data = libc::CMSG_DATA(hdr) as *const c_int;
+                    parsed.first_fd = Some(std::ptr::read_unaligned(data));
+                }
+                parsed.fd_count += count;
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            libc::SCM_CREDENTIALS => {
+                parsed.other = true;
+                let size = mem::size_of::<libc::ucred>();
+                if len < libc::CMSG_LEN(size as u32) as usize || data_room(msg, hdr) < size {
+                    short = true;
+                } else {
+                    let ucred: libc::ucred =
+                        std::ptr::read_unaligned(libc::CMSG_DATA(hdr) as *const _);
+                    parsed.creds = Some(UCred {
+                        pid: Some(ucred.pid),
+                        uid: ucred.uid,
+                        gid: ucred.gid,
+                    });
+                }
+            }
+            _ => parsed.other = true,
+        }
+    }
+    // keep going after a bad header, so callers know every fd to close
+    if short {
+        return Err(PassFdError::BadCmsgLen);
+    }
+    Ok(parsed)
+}
+
+// Take ownership of every descriptor in the message, in order
+pub(crate) unsafe fn msg_fds(msg: &msghdr) -> impl Iterator<Item = OwnedFd> + '_ {
+    rights(msg).map(|fd| OwnedFd::from_raw_fd(fd))
+}
@@ -0,0 +1,137 @@
+//! Error type describing what went wrong with a received message.
+//!
+//! All methods return `std::io::Error`. Failures detected by this crate
+//! carry a `PassFdError` inside, which can be recovered with
+//! `PassFdError::from`.
+
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// Failure modes of fd passing
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PassFdError {
+    /// Message carried no SCM_RIGHTS control message
+    NoControlMessage,
+    /// Control message has unexpected level or type
+    WrongCmsgType,
+    /// Control message has unexpected length
+    BadCmsgLen,
+    /// SCM_RIGHTS control message carries no descriptors
+    NoFds,
+    /// Control buffer was too small, kernel dropped some descriptors.
+    /// `delivered` of them did arrive and were closed.
+    Truncated { delivered: usize },
+    /// Peer sent more descriptors than asked for
+    TooManyFds,
+    /// Message is shorter than its length prefix
+    ShortMessage,
+    /// Length prefix of a frame is above `MAX_FRAME_LEN`
+    FrameTooLong { len: u64 },
+    /// Fewer payload bytes arrived than expected
+    ShortPayload,
+    /// Peer answered with something else than an acknowledgement
+    BadAck,
+    /// Message carried no SCM_CREDENTIALS control message
+    NoCredentials,
+    /// Descriptor is not a socket at all
+    NotSocket,
+    /// Descriptor is not an AF_UNIX stream, datagram or seqpacket socket
+    NotUnixSocket,
+    /// Descriptor to send is not open
+    InvalidFd,
+    /// Descriptor table is full (`EMFILE`/`ENFILE`), so the kernel dropped
+    /// the received fds. They are lost for the sender too, so the protocol
+    /// has to ask for them again after making room.
+    ///
+    /// A truncated control buffer is told apart from a full table with a
+    /// probe made after the receive, so it's best effort: if other threads
+    /// open or close fds in between, one can be reported as the other.
+    FdTableFull,
+    /// Peer closed the connection
+    UnexpectedEof,
+    /// No message arrived in time
+    TimedOut,
+    /// Non-blocking stream socket filled up in the middle of the payload.
+    /// The fds were delivered with the first `sent` bytes, only the rest
+    /// is missing: send that, not the fds again.
+    PartialSend { sent: usize },
+    /// Error returned by the OS
+    Io(io::Error),
+}
+
+impl fmt::Display for PassFdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassFdError::NoControlMessage => f.write_str("bad control msg (header)"),
+            PassFdError::WrongCmsgType => f.write_str("bad control msg (level)"),
+            PassFdError::BadCmsgLen => f.write_str("bad control msg (len)"),
+            PassFdError::NoFds => f.write_str("no descriptors in control message"),
+            PassFdError::Truncated { delivered } => write!(
+                f,
+                "control message truncated, {} fds delivered and closed, fds may have leaked",
+                delivered
+            ),
+            PassFdError::TooManyFds => f.write_str("too many fds in control message"),
+            PassFdError::ShortMessage => f.write_str("message shorter than its length prefix"),
+            PassFdError::FrameTooLong { len } => write!(
+                f,
+                "frame of {} bytes, at most {} allowed (MAX_FRAME_LEN)",
+                len,
+                crate::MAX_FRAME_LEN
+            ),
+            PassFdError::ShortPayload => f.write_str("payload shorter than expected"),
+            PassFdError::BadAck => f.write_str("unexpected acknowledgement"),
+            PassFdError::NoCredentials => f.write_str("no credentials in control message"),
+            PassFdError::NotSocket => {
+                f.write_str("descriptor is not a socket; fd passing requires an AF_UNIX socket")
+            }
+            PassFdError::NotUnixSocket => f.write_str("not a unix socket, can't pass fds"),
+            PassFdError::InvalidFd => f.write_str("invalid descriptor, not open"),
+            PassFdError::FdTableFull => f.write_str("descriptor table full, received fds dropped"),
+            PassFdError::UnexpectedEof => f.write_str("0 bytes read"),
+            PassFdError::TimedOut => f.write_str("timed out waiting for fd"),
+            PassFdError::PartialSend { sent } => write!(
+                f,
+                "fds sent with the first {} payload bytes, the rest would block",
+                sent
+            ),
+            PassFdError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for PassFdError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PassFdError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PassFdError {
+    fn from(err: io::Error) -> PassFdError {
+        if err.get_ref().is_some_and(|inner| inner.is::<PassFdError>()) {
+            let inner = err.into_inner().unwrap();
+            return *inner.downcast::<PassFdError>().unwrap();
+        }
+        PassFdError::Io(err)
+    }
+}
+
+impl From<PassFdError> for io::Error {
+    fn from(err: PassFdError) -> io::Error {
+        match err {
+            PassFdError::Io(err) => err,
+            PassFdError::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, err),
+            PassFdError::TimedOut => io::Error::new(ErrorKind::TimedOut, err),
+            PassFdError::FdTableFull | PassFdError::PartialSend { .. } => io::Error::other(err),
+            PassFdError::NotSocket | PassFdError::NotUnixSocket | PassFdError::InvalidFd => {
+                io::Error::new(ErrorKind::InvalidInput, err)
+            }
+            err => io::Error::new(ErrorKind::InvalidData, err),
+        }
+    }
+}
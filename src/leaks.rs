@@ -0,0 +1,47 @@
+//! Bookkeeping of received descriptors, to catch ones never closed.
+//!
+//! Only debug builds keep the registry, in release builds nothing is
+//! recorded and `check_leaks` always comes back empty.
+
+use std::collections::BTreeSet;
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, PoisonError};
+
+// Every fd received since it was last seen closed
+static RECEIVED: Mutex<BTreeSet<RawFd>> = Mutex::new(BTreeSet::new());
+
+pub(crate) fn track(fd: RawFd) {
+    if cfg!(debug_assertions) {
+        lock().insert(fd);
+    }
+}
+
+// The crate closed `fd` itself, its number may be reused by anyone
+pub(crate) fn untrack(fd: RawFd) {
+    if cfg!(debug_assertions) {
+        lock().remove(&fd);
+    }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, BTreeSet<RawFd>> {
+    // a panic elsewhere can't leave the set inconsistent
+    RECEIVED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Received descriptors that are still open, in ascending order.
+///
+/// Every fd the crate has received is recorded, and probed here with
+/// `fcntl(F_GETFD)`; closed ones are forgotten. Call it at the end of a
+/// test, when all received fds should be gone. A number closed and then
+/// reused by an unrelated `open` is reported too, so check where nothing
+/// else is opening fds. With the `tracing` feature each one is also logged
+/// as a warning.
+pub fn check_leaks() -> Vec<RawFd> {
+    let mut received = lock();
+    received.retain(|fd| unsafe { libc::fcntl(*fd, libc::F_GETFD) } >= 0);
+    #[cfg(feature = "tracing")]
+    for fd in received.iter() {
+        tracing::warn!(fd = *fd, "received descriptor still open");
+    }
+    received.iter().copied().collect()
+}
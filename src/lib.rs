@@ -1,9 +1,28 @@
 //! `passfd` allows passing file descriptors between unrelated processes
 //! using Unix sockets.
 //!
-//! Both tokio 0.1 and 0.2 are supported with `tokio_01` and `tokio_02`
-//! features. Please note that these features rely on internal representation
-//! of UnixStream and are unsafe.
+//! Async runtimes are supported with features:
+//! * `async` for tokio 1
+//! * `async_std` for async-std
+//! * `async_io` for async-io `Async<UnixStream>` (smol)
+//!
+//! With the `tracing` feature every `sendmsg`/`recvmsg` emits a `tracing`
+//! event with the socket, number of fds and payload length, or the errno
+//! on failure.
+//!
+//! The `leak_check` feature records every received descriptor in debug
+//! builds, and `check_leaks` lists the ones still open, e.g. at the end of
+//! a test.
+//!
+//! Without the default `std` feature the crate is `no_std` and only the
+//! `raw` module is available, which needs nothing but libc.
+//!
+//! The default `recv_cloexec` feature makes received descriptors
+//! close-on-exec, atomically where the platform allows. Turning it off
+//! leaves them as the kernel delivers them, inheritable by any program the
+//! process, or another of its threads, `exec`s next; only do that if every
+//! receiver sets `FD_CLOEXEC` itself or wants children to inherit the fds.
+//! The API stays the same. `raw::recv_fds` always sets `FD_CLOEXEC`.
 //!
 //! # Example usage
 //! ## Process 1 (sender)
@@ -21,226 +40,4107 @@
 //! ## Process 2 (receiver)
 //! ```no_run
 //! use passfd::FdPassingExt;
-//! use std::fs::File;
 //! use std::io::Read;
-//! use std::os::unix::io::FromRawFd;
 //! use std::os::unix::net::UnixStream;
 //!
 //! let stream = UnixStream::connect("/tmp/test.sock").unwrap();
-//! let fd = stream.recv_fd().unwrap();
-//! let mut file = unsafe { File::from_raw_fd(fd) };
+//! let mut file = stream.recv_file().unwrap();
 //! let mut buf = String::new();
 //! file.read_to_string(&mut buf).unwrap();
 //! println!("{}", buf);
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 use libc::{self, c_int, c_void, msghdr};
-use std::io::{Error, ErrorKind};
+#[cfg(feature = "std")]
+use std::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufWriter, Error, ErrorKind, IoSlice, IoSliceMut, Write};
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
 use std::mem::MaybeUninit;
-use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::net::UnixStream;
+#[cfg(feature = "std")]
+use std::net::{TcpStream, UdpSocket};
+#[cfg(feature = "std")]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+#[cfg(feature = "std")]
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixStream};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(all(feature = "std", target_os = "android"))]
+use std::os::android::net::SocketAddrExt;
+#[cfg(all(feature = "std", target_os = "linux"))]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+use std::os::unix::net::UnixListener;
+
+pub mod raw;
+
+// Flags for every recvmsg of the std API, without the `recv_cloexec`
+// feature fds are left as the kernel delivers them
+#[cfg(feature = "std")]
+const RECV_FLAGS: c_int = if cfg!(feature = "recv_cloexec") {
+    raw::RECV_FLAGS
+} else {
+    0
+};
+
+#[cfg(feature = "std")]
+mod addr;
+#[cfg(feature = "std")]
+use addr::UnixAddr;
+#[cfg(feature = "std")]
+mod buffer;
+#[cfg(feature = "std")]
+mod channel;
+#[cfg(feature = "std")]
+mod control;
+#[cfg(feature = "std")]
+use control::msg_fds;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "leak_check")]
+mod leaks;
+#[cfg(feature = "std")]
+mod message;
+#[cfg(feature = "std")]
+mod meta;
+#[cfg(feature = "std")]
+mod received;
+#[cfg(feature = "std")]
+pub use buffer::{aligned_control_buffer, control_buffer_size, AlignedBuffer, AlignedCmsgBuf};
+#[cfg(feature = "std")]
+pub use channel::FdChannel;
+#[cfg(feature = "std")]
+pub use control::{parse_control, ParsedControl};
+#[cfg(feature = "std")]
+pub use error::PassFdError;
+#[cfg(feature = "leak_check")]
+pub use leaks::check_leaks;
+#[cfg(feature = "std")]
+pub use message::{MessageBuilder, ReceivedMessage};
+#[cfg(feature = "std")]
+pub use meta::{AsBytes, FromBytes};
+#[cfg(feature = "std")]
+pub use received::ReceivedFds;
+
+/// Most fds `send_fds` and friends pass in one message, see `raw::MAX_FDS`.
+/// Bigger batches have to be split into several sends.
+pub const MAX_FDS_PER_MESSAGE: usize = raw::MAX_FDS;
+
+/// Longest frame body `recv_fd_framed` and `recv_message` accept. The
+/// length prefix comes from the peer, so it's checked against this before
+/// anything is allocated.
+#[cfg(feature = "std")]
+pub const MAX_FRAME_LEN: usize = 64 << 20;
 
 // Support for tokio 1
 #[cfg(feature = "async")]
 pub mod tokio;
 
-/// Main trait, extends UnixStream
+// Support for async-std
+#[cfg(feature = "async_std")]
+pub mod async_std;
+
+// Support for async-io (smol)
+#[cfg(feature = "async_io")]
+pub mod async_io;
+
+/// Main trait, extends UnixStream, UnixDatagram and io-safe OwnedFd/BorrowedFd
+///
+/// The RawFd implementation works with any Unix socket type, including
+/// `SOCK_SEQPACKET`. On sockets preserving message boundaries, payload
+/// bytes that don't fit the receive buffer are discarded with the message.
+///
+/// Descriptors go straight to the socket with `sendmsg`, past any
+/// userspace buffering. With a `BufWriter` around the socket, bytes written
+/// before the fd may still sit in the buffer and reach the peer after it;
+/// `send_fd_after_flush` flushes first.
+///
+/// If the peer has closed its end, sends fail with `ErrorKind::BrokenPipe`
+/// and no `SIGPIPE` is raised, so a sender can simply stop there.
+///
+/// Once a send returns `Ok`, the fds are in the peer's receive queue: Unix
+/// sockets have no send buffer, the message holds its own references to
+/// the files. Closing the sent fds, or the socket itself, right after is
+/// fine, the peer still receives them. Fds in a message the peer never
+/// reads are closed when its end is. A send the kernel refuses, e.g. with
+/// `WouldBlock` on a full non-blocking socket, queues no fds at all, so the
+/// caller has to retry it or wait; `send_fd_blocking_once` does the latter.
+///
+/// Methods take `&self` and keep no state between calls, every control
+/// buffer lives on the caller's stack. One socket can be shared by many
+/// threads sending at once: each fd goes out in its own `sendmsg`, and
+/// messages with payloads that fit the socket buffer don't interleave.
+///
+/// The trait is object safe, so sockets of different types, e.g.
+/// `UnixStream` or `UnixDatagram` picked at runtime, can be held as
+/// `Box<dyn FdPassingExt>`. The methods that need `Self: Sized`,
+/// `send_borrowed_fd`, `send_fd_with`, `recv_fd_with`, `incoming_fds` and
+/// `for_each_fd`, can't be called through it; the rest can.
+#[cfg(feature = "std")]
 pub trait FdPassingExt {
     /// Send RawFd. No type information is transmitted.
     fn send_fd(&self, fd: RawFd) -> Result<(), Error> {
         let dummy_payload = [0u8; mem::size_of::<c_int>()];
         self.send_fd_with_payload(fd, &dummy_payload[..])
     }
+    /// Send RawFd after checking it's open with `can_send`.
+    ///
+    /// A closed or never opened fd gives `PassFdError::InvalidFd` instead
+    /// of the `EBADF` from `sendmsg`, which could be about the socket too.
+    fn send_fd_checked(&self, fd: RawFd) -> Result<(), Error> {
+        if !can_send(fd) {
+            return Err(PassFdError::InvalidFd.into());
+        }
+        self.send_fd(fd)
+    }
+    /// Send the descriptor of anything implementing `AsFd`, keeping the
+    /// owner borrowed for the duration of the call.
+    fn send_borrowed_fd<F: AsFd>(&self, fd: F) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.send_fd(fd.as_fd().as_raw_fd())
+    }
     /// Send RawFd. With custom payload to be nice to some receivers.
-    fn send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error>;
+    ///
+    /// The fd goes with the first segment of the payload. If a stream
+    /// socket takes only part of it, the rest is written after, so there's
+    /// no size limit; see `send_fds`.
+    fn send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error> {
+        self.send_fds(&[fd], payload)
+    }
+    /// Send RawFd with a fixed-size metadata value as the payload, e.g. the
+    /// intended open mode or an offset to seek to.
+    ///
+    /// The receiver reads it back with `recv_fd_with`.
+    fn send_fd_with<T: AsBytes>(&self, fd: RawFd, meta: &T) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.send_fd_with_payload(fd, meta.as_bytes())
+    }
+    /// Send RawFd with custom payload, returning how many payload bytes
+    /// the kernel accepted.
+    ///
+    /// The fd is all-or-nothing, but on stream sockets the payload may be
+    /// written partially.
+    fn try_send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<usize, Error>;
+    /// Send RawFd with custom payload, passing `flags` through to `sendmsg`.
+    ///
+    /// `MSG_DONTWAIT` makes a single call non-blocking on any socket type.
+    /// `MSG_MORE` (Linux) only makes sense on stream sockets, `MSG_EOR`
+    /// only on `SOCK_SEQPACKET`. `MSG_NOSIGNAL` is always added where
+    /// supported.
+    fn send_fd_with_flags(&self, fd: RawFd, payload: &[u8], flags: c_int) -> Result<(), Error>;
+    /// Send RawFd with a payload gathered from several buffers, like
+    /// `Write::write_vectored`.
+    fn send_fd_vectored(&self, fd: RawFd, bufs: &[IoSlice]) -> Result<(), Error>;
+    /// Send several RawFds in a single message, so the receiver gets them
+    /// together.
+    ///
+    /// If a stream socket takes only part of the payload, the rest is
+    /// written after it, so all of it is delivered or an error is returned.
+    /// A `sendmsg` writing nothing at all is reported as
+    /// `ErrorKind::WriteZero`. More than `MAX_FDS_PER_MESSAGE` fds are
+    /// refused upfront with `ErrorKind::InvalidInput`, instead of the
+    /// `EINVAL` Linux would return.
+    ///
+    /// On a non-blocking socket `ErrorKind::WouldBlock` means nothing was
+    /// sent, fds included. If the socket fills up after the fds went out
+    /// with the first part of the payload, `PassFdError::PartialSend` is
+    /// returned instead, with how many bytes made it; only the remaining
+    /// payload should be sent again.
+    fn send_fds(&self, fds: &[RawFd], payload: &[u8]) -> Result<(), Error>;
+    /// Shut down the sending side with `shutdown(SHUT_WR)`, after the last
+    /// descriptor was sent.
+    ///
+    /// The peer's receive loop then sees a clean EOF, e.g. `recv_fd_opt`
+    /// returns `None`, while this end can still read, say, acks.
+    fn finish_sending(&self) -> Result<(), Error>;
+    /// Send RawFd and block until the peer acknowledges it with
+    /// `recv_fd_with_ack`.
+    ///
+    /// Fails with `ErrorKind::UnexpectedEof` if the peer closes the
+    /// connection instead, and `ErrorKind::InvalidData` if anything else
+    /// than the ack byte arrives.
+    fn send_fd_with_ack(&self, fd: RawFd) -> Result<(), Error>;
+    /// Send RawFd with the first part of `payload`, then keep writing until
+    /// all of it is delivered, like `Write::write_all`.
+    fn send_fd_all(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error>;
+    /// Send RawFd to `addr`, for datagram sockets that aren't connected.
+    fn send_fd_to(&self, fd: RawFd, addr: &SocketAddr) -> Result<(), Error>;
+    /// Send `body` prefixed with its length as a native-endian u32, with
+    /// `fd` attached if given. Counterpart of `recv_message`.
+    ///
+    /// On `SOCK_SEQPACKET` the whole frame is kept in a single message.
+    /// Bodies above `MAX_FRAME_LEN` are refused with
+    /// `ErrorKind::InvalidInput`.
+    fn send_message(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error>;
+    /// Like `send_message`, but the length prefix is a big-endian u64, as
+    /// many protocols frame their messages. Counterpart of
+    /// `recv_fd_framed`. Bodies above `MAX_FRAME_LEN` are refused with
+    /// `ErrorKind::InvalidInput`.
+    fn send_fd_framed(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error>;
     /// Receive RawFd. No type information is transmitted.
-    fn recv_fd(&self) -> Result<RawFd, Error>;
+    ///
+    /// If the process is out of descriptors, `PassFdError::FdTableFull` is
+    /// returned. The kernel has already dropped the fd by then, the sender
+    /// has to send it again once the receiver made room.
+    fn recv_fd(&self) -> Result<RawFd, Error> {
+        self.recv_owned_fd().map(IntoRawFd::into_raw_fd)
+    }
+    /// Receive OwnedFd, which is closed automatically on drop.
+    fn recv_owned_fd(&self) -> Result<OwnedFd, Error>;
+    /// Receive RawFd numbered `min` or above, e.g. 3 to keep stdio free.
+    ///
+    /// A lower fd is moved up with `fcntl(F_DUPFD)` and the original is
+    /// closed. The copy keeps `FD_CLOEXEC` if the received fd had it.
+    fn recv_fd_at_least(&self, min: RawFd) -> Result<RawFd, Error> {
+        let fd = self.recv_owned_fd()?;
+        if fd.as_raw_fd() >= min {
+            return Ok(fd.into_raw_fd());
+        }
+        let moved = dup_at_least(fd.as_fd(), min);
+        // only the copy goes to the caller
+        discard(fd);
+        moved.map(|moved| hand_over(moved).into_raw_fd())
+    }
+    /// Receive a descriptor together with its file type, from `fstat`.
+    ///
+    /// If `fstat` fails the descriptor is closed and the error returned.
+    fn recv_fd_typed(&self) -> Result<(OwnedFd, FileType), Error> {
+        let fd = self.recv_owned_fd()?;
+        let kind = match FileType::of(fd.as_fd()) {
+            Ok(kind) => kind,
+            Err(err) => {
+                discard(fd);
+                return Err(err);
+            }
+        };
+        Ok((fd, kind))
+    }
+    /// Receive RawFd together with the address of the sender, so a
+    /// datagram server can reply with `send_fd_to`.
+    ///
+    /// A sender that isn't bound, e.g. one end of `UnixDatagram::pair` or
+    /// an `unbound` socket, gives an address for which
+    /// `SocketAddr::is_unnamed` is true. There's no way to reply to it.
+    fn recv_fd_from(&self) -> Result<(RawFd, SocketAddr), Error>;
+    /// Send RawFd without blocking, even if the socket is in blocking mode.
+    ///
+    /// `ErrorKind::WouldBlock` is returned as is, never retried. Together
+    /// with `recv_fd_nonblocking` this is enough to drive fd passing from
+    /// any event loop: register the socket with epoll/kqueue, and when it
+    /// reports the socket writable (readable), call the method again until
+    /// it stops returning `WouldBlock`.
+    fn send_fd_nonblocking(&self, fd: RawFd) -> Result<(), Error> {
+        let dummy_payload = [0u8; mem::size_of::<c_int>()];
+        self.send_fd_with_flags(fd, &dummy_payload, libc::MSG_DONTWAIT)
+    }
+    /// Send RawFd on a non-blocking socket, waiting for room if the
+    /// socket is full instead of failing with `WouldBlock`.
+    ///
+    /// Waits with `poll(POLLOUT)` and tries again, the socket's
+    /// `O_NONBLOCK` flag is left alone, so other threads or processes
+    /// sharing the socket aren't affected. When it returns `Ok` the fd is
+    /// queued, see the delivery notes above. On a blocking socket this is
+    /// just `send_fd`.
+    fn send_fd_blocking_once(&self, fd: RawFd) -> Result<(), Error>;
+    /// Receive RawFd without blocking, even if the socket is in blocking
+    /// mode. `ErrorKind::WouldBlock` is returned as is, see
+    /// `send_fd_nonblocking`.
+    fn recv_fd_nonblocking(&self) -> Result<RawFd, Error>;
+    /// Check whether the next queued message carries descriptors, leaving
+    /// it in the queue. `false` if nothing is queued, this never blocks.
+    ///
+    /// A `MSG_PEEK` receive with a control buffer does install the fds,
+    /// on Linux as fresh copies on every peek, so the peek here offers no
+    /// room for control data at all: the kernel then drops its references
+    /// and only reports `MSG_CTRUNC`, and no fd enters the process. With
+    /// `SO_PASSCRED` enabled every message carries credentials, which set
+    /// the same flag, so the answer is `true` for any message then.
+    ///
+    /// A closed peer gives `ErrorKind::UnexpectedEof`.
+    fn peek_fd_available(&self) -> Result<bool, Error>;
+    /// Iterator over the descriptors already queued on the socket, like
+    /// `UnixListener::incoming`.
+    ///
+    /// Calls `recv_fd_nonblocking` until it returns `WouldBlock`, which
+    /// ends the iteration. Any other error is yielded once and ends it too.
+    fn incoming_fds(&self) -> IncomingFds<'_, Self>
+    where
+        Self: Sized,
+    {
+        IncomingFds {
+            socket: self,
+            done: false,
+        }
+    }
+    /// Receive descriptors until the peer closes the connection, handing
+    /// each one to `f`, which owns it from then on.
+    ///
+    /// Orderly shutdown ends the loop with `Ok(())`. An error from `f` or
+    /// from receiving is returned as is and ends it too.
+    fn for_each_fd<F: FnMut(RawFd) -> Result<(), Error>>(&self, mut f: F) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        loop {
+            match self.recv_fd() {
+                Ok(fd) => f(fd)?,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    /// Receive RawFd from a non-blocking socket, returning `None` if
+    /// nothing is pending.
+    fn try_recv_fd(&self) -> Result<Option<RawFd>, Error> {
+        match self.recv_fd() {
+            Ok(fd) => Ok(Some(fd)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    /// Receive RawFd, returning `None` if the peer closed the connection.
+    ///
+    /// Convenient for loops draining a socket until the other side is done.
+    fn recv_fd_opt(&self) -> Result<Option<RawFd>, Error> {
+        match self.recv_fd() {
+            Ok(fd) => Ok(Some(fd)),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    /// Receive RawFd sent with `send_fd_with_ack` and acknowledge it.
+    fn recv_fd_with_ack(&self) -> Result<RawFd, Error> {
+        let fd = self.recv_owned_fd()?;
+        if let Err(err) = self.send_fds(&[], &[ACK]) {
+            discard(fd);
+            return Err(err);
+        }
+        Ok(fd.into_raw_fd())
+    }
+    /// Receive a descriptor and install it at `target`, like `dup2`.
+    ///
+    /// Whatever `target` referred to before is closed. The installed
+    /// descriptor is close-on-exec, clear `FD_CLOEXEC` if a child should
+    /// inherit it.
+    fn recv_fd_into(&self, target: RawFd) -> Result<(), Error> {
+        let fd = self.recv_owned_fd()?;
+        if fd.as_raw_fd() == target {
+            // landed there already, target was free
+            let _ = fd.into_raw_fd();
+            return Ok(());
+        }
+        let result = dup_cloexec(fd.as_raw_fd(), target);
+        // only target goes to the caller
+        discard(fd);
+        result?;
+        #[cfg(feature = "leak_check")]
+        leaks::track(target);
+        Ok(())
+    }
+    /// Receive a descriptor as a File.
+    fn recv_file(&self) -> Result<File, Error> {
+        self.recv_owned_fd().map(File::from)
+    }
+    /// Receive a descriptor as a UnixStream.
+    fn recv_stream(&self) -> Result<UnixStream, Error> {
+        self.recv_owned_fd().map(UnixStream::from)
+    }
+    /// Receive a descriptor as a UnixStream, same as `recv_stream`.
+    ///
+    /// Like the other typed receivers, nothing checks what the descriptor
+    /// actually is; the protocol has to make sure the peer sent the right
+    /// kind of socket.
+    fn recv_unix_stream(&self) -> Result<UnixStream, Error> {
+        self.recv_stream()
+    }
+    /// Receive a descriptor as a TcpStream. The peer has to send a
+    /// connected TCP socket, see `recv_unix_stream`.
+    fn recv_tcp_stream(&self) -> Result<TcpStream, Error> {
+        self.recv_owned_fd().map(TcpStream::from)
+    }
+    /// Receive a descriptor as a UdpSocket. The peer has to send a UDP
+    /// socket, see `recv_unix_stream`.
+    fn recv_udp_socket(&self) -> Result<UdpSocket, Error> {
+        self.recv_owned_fd().map(UdpSocket::from)
+    }
+    /// Receive RawFd together with the payload it was sent with.
+    ///
+    /// The data portion of the message is read into `buf`, the number of
+    /// bytes read is returned alongside the fd.
+    ///
+    /// `(fd, 0)` means the peer sent the fd bare, with no data at all,
+    /// which is not an error. Only datagram and seqpacket sockets can carry
+    /// such a message; on a stream socket sending fds with an empty payload
+    /// fails with `ErrorKind::InvalidInput`.
+    fn recv_fd_with_payload(&self, buf: &mut [u8]) -> Result<(RawFd, usize), Error>;
+    /// Receive RawFd whose message carries exactly `expected.len()` payload
+    /// bytes, read into `expected`.
+    ///
+    /// If fewer bytes arrived with the fd, it's closed and an
+    /// `ErrorKind::InvalidData` error is returned.
+    fn recv_fd_expect_payload(&self, expected: &mut [u8]) -> Result<RawFd, Error> {
+        let (fd, n) = self.recv_fd_with_payload(expected)?;
+        if n < expected.len() {
+            discard(unsafe { OwnedFd::from_raw_fd(fd) });
+            return Err(PassFdError::ShortPayload.into());
+        }
+        Ok(fd)
+    }
+    /// Send RawFd with the current `CLOCK_MONOTONIC` time as the payload,
+    /// so the receiver can tell how long the handoff took with
+    /// `recv_fd_timestamped`.
+    ///
+    /// The monotonic clock is only comparable on the same machine, and
+    /// within the same time namespace on Linux.
+    fn send_fd_timestamped(&self, fd: RawFd) -> Result<(), Error> {
+        let nanos = monotonic_now().as_nanos() as u64;
+        self.send_fd_with_payload(fd, &nanos.to_ne_bytes())
+    }
+    /// Receive RawFd sent by `send_fd_timestamped`, together with the time
+    /// elapsed since it was sent.
+    ///
+    /// A message without the 8-byte timestamp is refused like in
+    /// `recv_fd_expect_payload`. A timestamp from the future gives zero.
+    fn recv_fd_timestamped(&self) -> Result<(RawFd, Duration), Error> {
+        let mut buf = [0u8; 8];
+        let fd = self.recv_fd_expect_payload(&mut buf)?;
+        let sent = Duration::from_nanos(u64::from_ne_bytes(buf));
+        Ok((fd, monotonic_now().saturating_sub(sent)))
+    }
+    /// Receive RawFd together with the metadata value sent by `send_fd_with`.
+    ///
+    /// If the payload is shorter than `T`, the fd is closed and an
+    /// `ErrorKind::InvalidData` error is returned.
+    fn recv_fd_with<T: FromBytes>(&self) -> Result<(RawFd, T), Error>
+    where
+        Self: Sized,
+    {
+        let mut buf = vec![0u8; mem::size_of::<T>()];
+        let fd = self.recv_fd_expect_payload(&mut buf)?;
+        // length was checked above
+        Ok((fd, T::read_from(&buf).unwrap()))
+    }
+    /// Receive RawFd and exactly `payload.len()` bytes of data, passing
+    /// `MSG_WAITALL` to `recvmsg` so a short read doesn't come back early.
+    ///
+    /// The fd arrives with the first segment of the data, and Linux returns
+    /// from `recvmsg` right after a segment carrying fds even with
+    /// `MSG_WAITALL`, so on stream sockets the rest is read with further
+    /// calls. On datagram and seqpacket sockets `MSG_WAITALL` makes no
+    /// difference, a message is read whole; a shorter one closes the fd
+    /// and returns an `ErrorKind::InvalidData` error.
+    fn recv_fd_waitall(&self, payload: &mut [u8]) -> Result<RawFd, Error>;
+    /// Receive a frame sent with `send_message`, together with the fd it
+    /// carried, if any.
+    ///
+    /// A length prefix above `MAX_FRAME_LEN` fails with
+    /// `PassFdError::FrameTooLong` and the fd is closed, see
+    /// `recv_fd_framed`.
+    fn recv_message(&self) -> Result<(Option<RawFd>, Vec<u8>), Error>;
+    /// Receive a frame sent with `send_fd_framed`, together with the fd it
+    /// carried, if any.
+    ///
+    /// A length prefix above `MAX_FRAME_LEN` fails with
+    /// `PassFdError::FrameTooLong` and the fd is closed. On a stream
+    /// socket the body is left unread, so the connection can't be used
+    /// for frames after that.
+    fn recv_fd_framed(&self) -> Result<(Option<RawFd>, Vec<u8>), Error>;
+    /// Receive RawFd without setting `FD_CLOEXEC` on it.
+    ///
+    /// The descriptor survives `exec`, e.g. to hand a listener down to a
+    /// re-exec'd copy of the program. The caller is responsible for the
+    /// descriptor's exec behavior.
+    fn recv_fd_raw(&self) -> Result<RawFd, Error>;
+    /// Receive RawFd together with `msg_flags` as set by `recvmsg`, e.g.
+    /// `MSG_TRUNC` if a datagram carried more data than was read, or
+    /// `MSG_EOR` at a record boundary on `SOCK_SEQPACKET`.
+    fn recv_fd_detailed(&self) -> Result<(RawFd, c_int), Error>;
+    /// Receive RawFd using `cmsg_buf` as the control buffer, so nothing is
+    /// allocated per call.
+    ///
+    /// The buffer must be aligned for `cmsghdr` and fit one fd, otherwise
+    /// `ErrorKind::InvalidInput` is returned. `aligned_control_buffer(1)`
+    /// gives one that does, or `AlignedCmsgBuf` sized with
+    /// `control_buffer_size(1)` on the stack.
+    fn recv_fd_with_buffer(&self, cmsg_buf: &mut [u8]) -> Result<RawFd, Error>;
+    /// Receive RawFd, giving up with `ErrorKind::TimedOut` if nothing
+    /// arrives within `timeout`.
+    fn recv_fd_timeout(&self, timeout: Duration) -> Result<RawFd, Error>;
+    /// Receive RawFd, giving up with `ErrorKind::TimedOut` if nothing
+    /// arrives by `deadline`.
+    ///
+    /// Waits with `poll`, so the socket's blocking mode and timeouts are
+    /// left alone, which matters when the socket is shared. A signal
+    /// interrupting the wait doesn't extend it, and neither does another
+    /// reader taking the message first: the receive after `poll` doesn't
+    /// block, it goes back to waiting.
+    fn recv_fd_deadline(&self, deadline: Instant) -> Result<RawFd, Error>;
+    /// Receive up to `max` RawFds sent in a single message.
+    ///
+    /// If the sender transmitted fewer than `max` descriptors, only those
+    /// are returned. If it transmitted more, all of them are closed and an
+    /// error is returned. All returned descriptors have `FD_CLOEXEC` set,
+    /// unless the `recv_cloexec` feature is off.
+    ///
+    /// A `max` above `MAX_FDS_PER_MESSAGE` is refused with
+    /// `ErrorKind::InvalidInput` before anything is received.
+    fn recv_fds(&self, max: usize) -> Result<Vec<RawFd>, Error> {
+        let (fds, count) = self.recv_fds_counted(max)?;
+        if count > fds.len() {
+            for fd in fds {
+                discard(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+            return Err(PassFdError::TooManyFds.into());
+        }
+        Ok(fds)
+    }
+    /// Receive up to `max` RawFds sent in a single message, together with
+    /// how many descriptors the message actually carried.
+    ///
+    /// If the count is bigger than the number of returned fds, the sender
+    /// transmitted more than `max` and the extra ones were closed. When
+    /// even the control buffer's padding couldn't hold them, the error is
+    /// `PassFdError::Truncated`, whose `delivered` is the kernel's count of
+    /// the fds that did fit; the sender's full count is lost.
+    fn recv_fds_counted(&self, max: usize) -> Result<(Vec<RawFd>, usize), Error>;
+    /// Receive up to `out.len()` RawFds sent in a single message into
+    /// `out`, returning how many were stored, like `Read::read`.
+    ///
+    /// Extra descriptors that fit in the control buffer's padding are
+    /// closed; if more arrived, the kernel drops them and
+    /// `PassFdError::Truncated` is returned. Nothing is allocated unless
+    /// `out` is longer than a dozen or so fds, so a stack array makes
+    /// batch receiving allocation-free.
+    fn recv_fds_into(&self, out: &mut [RawFd]) -> Result<usize, Error>;
+    /// Receive up to `max` fds like `recv_fds`, owned by a guard that
+    /// closes whatever isn't taken out of it.
+    fn recv_fds_guarded(&self, max: usize) -> Result<ReceivedFds, Error> {
+        let fds = self.recv_fds(max)?;
+        Ok(ReceivedFds::new(
+            fds.into_iter()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+                .collect(),
+        ))
+    }
+    /// Receive RawFd together with the credentials of the sending process.
+    ///
+    /// Enables `SO_PASSCRED` on the socket. The kernel only attaches
+    /// credentials to messages sent while the option is on, so for the very
+    /// first message it has to be enabled before the peer sends.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_fd_with_creds(&self) -> Result<(RawFd, UCred), Error>;
+    /// Send RawFd together with credentials in a `SCM_CREDENTIALS`
+    /// control message, which the kernel verifies.
+    ///
+    /// Without privileges they must be the sender's own; a missing pid is
+    /// filled in with the current one. The receiver needs `SO_PASSCRED`
+    /// enabled to get them, see `recv_fd_with_creds`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_fd_with_creds(&self, fd: RawFd, creds: UCred) -> Result<(), Error>;
+    /// Hand over a descriptor and assert the sender's identity in the same
+    /// message, e.g. the first request to a privileged daemon.
+    ///
+    /// Same as `send_fd_with_creds`: the kernel refuses credentials the
+    /// sender isn't allowed to claim. Counterpart of
+    /// `recv_fd_authenticated`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_fd_authenticated(&self, fd: RawFd, creds: UCred) -> Result<(), Error> {
+        self.send_fd_with_creds(fd, creds)
+    }
+    /// Receive a descriptor together with kernel-verified credentials of
+    /// the process that sent it.
+    ///
+    /// Unlike `recv_fd_with_creds`, credentials the kernel didn't fill in
+    /// are refused: if `SO_PASSCRED` was off when the peer sent the
+    /// message and the peer attached none, Linux reports pid 0 and the
+    /// overflow uid and gid, usually `nobody`, which must not be taken for
+    /// a real identity. That fails with
+    /// `PassFdError::NoCredentials` and the fd is closed. Enable
+    /// `SO_PASSCRED` before the peer sends, or have it use
+    /// `send_fd_authenticated`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_fd_authenticated(&self) -> Result<(OwnedFd, UCred), Error> {
+        let (fd, creds) = self.recv_fd_with_creds()?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if creds.pid == Some(0) {
+            discard(fd);
+            return Err(PassFdError::NoCredentials.into());
+        }
+        Ok((fd, creds))
+    }
+    /// Credentials of the connected peer, without receiving anything.
+    ///
+    /// Uses `SO_PEERCRED` on Linux and Haiku, `getpeerucred` on illumos and
+    /// Solaris, and `getpeereid` elsewhere, where pid is not available.
+    /// UnixStream has an unstable inherent method with the
+    /// same name, call this one as `FdPassingExt::peer_cred(&stream)`.
+    fn peer_cred(&self) -> Result<UCred, Error>;
+    /// Check that the descriptor is an `AF_UNIX` stream, datagram or
+    /// seqpacket socket, the only kind fds can be passed over.
+    ///
+    /// Returns `PassFdError::NotSocket` for anything that isn't a socket and
+    /// `PassFdError::NotUnixSocket` for other kinds of sockets, instead of
+    /// whatever errno `sendmsg` would come up with.
+    fn check_fd_passing(&self) -> Result<(), Error>;
+    /// Same as `check_fd_passing`, but returns bool.
+    fn supports_fd_passing(&self) -> bool {
+        self.check_fd_passing().is_ok()
+    }
 }
 
-impl FdPassingExt for UnixStream {
-    fn send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error> {
-        self.as_raw_fd().send_fd_with_payload(fd, payload)
+// Byte sent back by recv_fd_with_ack
+#[cfg(feature = "std")]
+const ACK: u8 = 0x06;
+
+/// Flush `writer`, then send RawFd over the socket it wraps, so the fd
+/// can't get ahead of bytes written before it.
+#[cfg(feature = "std")]
+pub fn send_fd_after_flush<S: Write + AsRawFd>(
+    writer: &mut BufWriter<S>,
+    fd: RawFd,
+) -> Result<(), Error> {
+    writer.flush()?;
+    writer.get_ref().as_raw_fd().send_fd(fd)
+}
+
+/// Check that `fd` is an open descriptor, so it can be sent.
+///
+/// Costs one `fcntl(F_GETFD)`. The fd can still be closed by another
+/// thread before it's sent.
+#[cfg(feature = "std")]
+pub fn can_send(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+}
+
+/// How many more descriptors the process can have open before receiving
+/// fails with `PassFdError::FdTableFull`, from `getrlimit(RLIMIT_NOFILE)`
+/// minus the fds open now.
+///
+/// Open fds are counted in `/proc/self/fd` on Linux and with
+/// `proc_pidinfo` on Apple platforms, elsewhere `ErrorKind::Unsupported`
+/// is returned. The answer is a snapshot, other threads can open or close
+/// fds right after; use it for backpressure, not as a guarantee.
+#[cfg(feature = "std")]
+pub fn remaining_fd_capacity() -> Result<usize, Error> {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return Err(Error::last_os_error());
     }
+    let limit = if limit.rlim_cur == libc::RLIM_INFINITY {
+        usize::MAX
+    } else {
+        usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)
+    };
+    Ok(limit.saturating_sub(open_fd_count()?))
+}
 
-    fn recv_fd(&self) -> Result<RawFd, Error> {
-        self.as_raw_fd().recv_fd()
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+fn open_fd_count() -> Result<usize, Error> {
+    // the directory itself is open while it's read
+    let count = std::fs::read_dir("/proc/self/fd")?.count();
+    Ok(count.saturating_sub(1))
+}
+
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+fn open_fd_count() -> Result<usize, Error> {
+    let pid = unsafe { libc::getpid() };
+    // without a buffer the size comes with some slack, so ask again
+    let size =
+        unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDLISTFDS, 0, std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return Err(Error::last_os_error());
+    }
+    let entry = mem::size_of::<libc::proc_fdinfo>();
+    let mut fds: Vec<libc::proc_fdinfo> = Vec::with_capacity(size as usize / entry);
+    let size = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDLISTFDS,
+            0,
+            fds.as_mut_ptr() as *mut c_void,
+            (fds.capacity() * entry) as c_int,
+        )
+    };
+    if size <= 0 {
+        return Err(Error::last_os_error());
     }
+    Ok(size as usize / entry)
 }
 
-// buffer must be aligned to header (See cmsg(3))
-#[repr(C)]
-union HeaderAlignedBuf {
-    // CMSG_SPACE(mem::size_of::<c_int>()) = 24 (linux x86_64),
-    // we leave some space just in case
-    // TODO: use CMSPG_SPACE when it's const fn
-    // https://github.com/rust-lang/rust/issues/64926
-    buf: [libc::c_char; 256],
-    align: libc::cmsghdr,
+#[cfg(all(
+    feature = "std",
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))
+))]
+fn open_fd_count() -> Result<usize, Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "counting open fds is not supported on this platform",
+    ))
 }
 
-impl FdPassingExt for RawFd {
-    fn send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error> {
-        let msg_len = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as _ };
-        let mut u = HeaderAlignedBuf { buf: [0; 256] };
-        let mut iov = libc::iovec {
-            iov_base: payload.as_ptr() as *mut u8 as *mut c_void,
-            iov_len: payload.len(),
-        };
+/// Connected pair of Unix stream sockets set up for fd passing, e.g.
+/// between a parent and a forked child.
+///
+/// Both ends are close-on-exec and, on Apple platforms, don't raise SIGPIPE.
+#[cfg(feature = "std")]
+pub fn pair_for_fd_passing() -> Result<(UnixStream, UnixStream), Error> {
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let ty = libc::SOCK_STREAM | libc::SOCK_CLOEXEC;
+    // no SOCK_CLOEXEC on Apple platforms, set below
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let ty = libc::SOCK_STREAM;
 
-        let mut msg: MaybeUninit<msghdr> = MaybeUninit::zeroed();
-        unsafe {
-            let msg_ptr = msg.as_mut_ptr();
-            (*msg_ptr).msg_name = std::ptr::null_mut();
-            (*msg_ptr).msg_namelen = 0;
-            (*msg_ptr).msg_iov = &mut iov;
-            (*msg_ptr).msg_iovlen = 1;
-            (*msg_ptr).msg_control = u.buf.as_mut_ptr() as *mut c_void;
-            (*msg_ptr).msg_controllen = msg_len;
-            (*msg_ptr).msg_flags = 0;
+    let mut fds = [-1; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, ty, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let pair = unsafe {
+        (
+            UnixStream::from_raw_fd(fds[0]),
+            UnixStream::from_raw_fd(fds[1]),
+        )
+    };
+    for fd in fds {
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
         }
-        let msg = unsafe { msg.assume_init() };
+        set_nosigpipe(fd)?;
+    }
+    Ok(pair)
+}
 
-        unsafe {
-            let mut hdr: MaybeUninit<libc::cmsghdr> = MaybeUninit::uninit();
-            {
-                let hdr = hdr.as_mut_ptr();
-                (*hdr).cmsg_level = libc::SOL_SOCKET;
-                (*hdr).cmsg_type = libc::SCM_RIGHTS;
-                (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
-            }
-            let hdr = hdr.assume_init();
-            // https://github.com/rust-lang/rust-clippy/issues/2881
-            #[allow(clippy::cast_ptr_alignment)]
-            std::ptr::write_unaligned(libc::CMSG_FIRSTHDR(&msg), hdr);
+/// Listen on an abstract Unix socket address, shown as `@name` by tools
+/// like `ss`.
+///
+/// Abstract addresses don't live in the filesystem, so there's nothing to
+/// clean up and no stale socket file to race with.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub fn bind_abstract(name: &[u8]) -> Result<UnixListener, Error> {
+    let addr = SocketAddr::from_abstract_name(name)?;
+    UnixListener::bind_addr(&addr)
+}
 
-            // https://github.com/rust-lang/rust-clippy/issues/2881
-            #[allow(clippy::cast_ptr_alignment)]
-            std::ptr::write_unaligned(
-                libc::CMSG_DATA(u.buf.as_mut_ptr() as *const _) as *mut c_int,
-                fd,
-            );
+/// Connect to an abstract Unix socket address bound with `bind_abstract`.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub fn connect_abstract(name: &[u8]) -> Result<UnixStream, Error> {
+    let addr = SocketAddr::from_abstract_name(name)?;
+    UnixStream::connect_addr(&addr)
+}
+
+/// Iterator returned by `FdPassingExt::incoming_fds`
+#[cfg(feature = "std")]
+pub struct IncomingFds<'a, T: ?Sized> {
+    socket: &'a T,
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<T: FdPassingExt + ?Sized> Iterator for IncomingFds<'_, T> {
+    type Item = Result<RawFd, Error>;
+
+    fn next(&mut self) -> Option<Result<RawFd, Error>> {
+        if self.done {
+            return None;
         }
+        match self.socket.recv_fd_nonblocking() {
+            Ok(fd) => Some(Ok(fd)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
 
-        let rv = unsafe { libc::sendmsg(*self, &msg, 0) };
-        if rv < 0 {
+/// Credentials of a peer process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(feature = "std")]
+pub struct UCred {
+    pub pid: Option<libc::pid_t>,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Type of the file behind a descriptor, see `recv_fd_typed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(feature = "std")]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Socket,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    /// No file type in `st_mode`, e.g. Linux eventfd or epoll fds, which
+    /// are backed by anonymous inodes
+    Unknown,
+}
+
+#[cfg(feature = "std")]
+impl FileType {
+    /// File type of an open descriptor, from `fstat`.
+    pub fn of(fd: BorrowedFd<'_>) -> Result<FileType, Error> {
+        let mut st = MaybeUninit::<libc::stat>::uninit();
+        if unsafe { libc::fstat(fd.as_raw_fd(), st.as_mut_ptr()) } < 0 {
             return Err(Error::last_os_error());
         }
+        let mode = unsafe { st.assume_init() }.st_mode;
+        Ok(match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::Regular,
+            libc::S_IFDIR => FileType::Directory,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFSOCK => FileType::Socket,
+            libc::S_IFIFO => FileType::Fifo,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            _ => FileType::Unknown,
+        })
+    }
+}
 
-        Ok(())
+// Sockets just forward to the RawFd implementation
+#[cfg(feature = "std")]
+macro_rules! impl_via_raw_fd {
+    ($($ty:ty),*) => {$(
+        impl FdPassingExt for $ty {
+            fn send_fds(&self, fds: &[RawFd], payload: &[u8]) -> Result<(), Error> {
+                self.as_raw_fd().send_fds(fds, payload)
+            }
+
+            fn try_send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<usize, Error> {
+                self.as_raw_fd().try_send_fd_with_payload(fd, payload)
+            }
+
+            fn send_fd_with_flags(&self, fd: RawFd, payload: &[u8], flags: c_int) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_with_flags(fd, payload, flags)
+            }
+
+            fn send_fd_vectored(&self, fd: RawFd, bufs: &[IoSlice]) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_vectored(fd, bufs)
+            }
+
+            fn send_fd_to(&self, fd: RawFd, addr: &SocketAddr) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_to(fd, addr)
+            }
+
+            fn recv_fd_from(&self) -> Result<(RawFd, SocketAddr), Error> {
+                self.as_raw_fd().recv_fd_from()
+            }
+
+            fn send_fd_with_ack(&self, fd: RawFd) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_with_ack(fd)
+            }
+
+            fn send_fd_all(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_all(fd, payload)
+            }
+
+            fn send_message(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error> {
+                self.as_raw_fd().send_message(fd, body)
+            }
+
+            fn send_fd_framed(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_framed(fd, body)
+            }
+
+            fn recv_owned_fd(&self) -> Result<OwnedFd, Error> {
+                self.as_raw_fd().recv_owned_fd()
+            }
+
+            fn send_fd_blocking_once(&self, fd: RawFd) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_blocking_once(fd)
+            }
+
+            fn recv_fd_nonblocking(&self) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_nonblocking()
+            }
+
+            fn peek_fd_available(&self) -> Result<bool, Error> {
+                self.as_raw_fd().peek_fd_available()
+            }
+
+            fn recv_message(&self) -> Result<(Option<RawFd>, Vec<u8>), Error> {
+                self.as_raw_fd().recv_message()
+            }
+
+            fn recv_fd_framed(&self) -> Result<(Option<RawFd>, Vec<u8>), Error> {
+                self.as_raw_fd().recv_fd_framed()
+            }
+
+            fn recv_fd_with_payload(&self, buf: &mut [u8]) -> Result<(RawFd, usize), Error> {
+                self.as_raw_fd().recv_fd_with_payload(buf)
+            }
+
+            fn recv_fd_raw(&self) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_raw()
+            }
+
+            fn recv_fd_detailed(&self) -> Result<(RawFd, c_int), Error> {
+                self.as_raw_fd().recv_fd_detailed()
+            }
+
+            fn recv_fd_waitall(&self, payload: &mut [u8]) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_waitall(payload)
+            }
+
+            fn finish_sending(&self) -> Result<(), Error> {
+                self.as_raw_fd().finish_sending()
+            }
+
+            fn recv_fd_with_buffer(&self, cmsg_buf: &mut [u8]) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_with_buffer(cmsg_buf)
+            }
+
+            fn recv_fd_timeout(&self, timeout: Duration) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_timeout(timeout)
+            }
+
+            fn recv_fd_deadline(&self, deadline: Instant) -> Result<RawFd, Error> {
+                self.as_raw_fd().recv_fd_deadline(deadline)
+            }
+
+            fn recv_fds_counted(&self, max: usize) -> Result<(Vec<RawFd>, usize), Error> {
+                self.as_raw_fd().recv_fds_counted(max)
+            }
+
+            fn recv_fds_into(&self, out: &mut [RawFd]) -> Result<usize, Error> {
+                self.as_raw_fd().recv_fds_into(out)
+            }
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            fn recv_fd_with_creds(&self) -> Result<(RawFd, UCred), Error> {
+                self.as_raw_fd().recv_fd_with_creds()
+            }
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            fn send_fd_with_creds(&self, fd: RawFd, creds: UCred) -> Result<(), Error> {
+                self.as_raw_fd().send_fd_with_creds(fd, creds)
+            }
+
+            fn peer_cred(&self) -> Result<UCred, Error> {
+                self.as_raw_fd().peer_cred()
+            }
+
+            fn check_fd_passing(&self) -> Result<(), Error> {
+                self.as_raw_fd().check_fd_passing()
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "std")]
+impl_via_raw_fd!(UnixStream, UnixDatagram, OwnedFd, BorrowedFd<'_>);
+
+// Headers kept on the stack, enough for a handful of fds
+#[cfg(feature = "std")]
+const INLINE_HDRS: usize = 4;
+
+// Exactly enough for a single fd, the common case. Data after the header
+// is aligned to at most usize, so this always covers CMSG_SPACE(sizeof int).
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[cfg(feature = "std")]
+struct SingleFd {
+    hdr: libc::cmsghdr,
+    data: usize,
+}
+
+// Fail the build rather than the assert_sized test if that ever stops being
+// true
+#[cfg(feature = "std")]
+const _: () = {
+    let space = control_buffer_size(1);
+    assert!(mem::size_of::<SingleFd>() >= space);
+    assert!(mem::align_of::<SingleFd>() >= mem::align_of::<libc::cmsghdr>());
+    assert!(INLINE_HDRS * mem::size_of::<libc::cmsghdr>() >= space);
+};
+
+// Storage must be aligned to header (See cmsg(3)), so it's made of headers
+#[cfg(feature = "std")]
+enum Storage {
+    Single(SingleFd),
+    Inline([libc::cmsghdr; INLINE_HDRS]),
+    Heap(Vec<libc::cmsghdr>),
+}
+
+// Size of a SCM_RIGHTS message with `count` fds, refusing counts that
+// could overflow CMSG_SPACE
+#[cfg(feature = "std")]
+fn rights_space(count: usize) -> Result<usize, Error> {
+    raw::rights_space(count).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} fds requested, at most {} fit in one message (MAX_FDS_PER_MESSAGE)",
+                count, MAX_FDS_PER_MESSAGE
+            ),
+        )
+    })
+}
+
+// Control buffer sized to the actual number of fds
+#[cfg(feature = "std")]
+struct ControlBuf {
+    storage: Storage,
+    len: usize,
+}
+
+#[cfg(feature = "std")]
+impl ControlBuf {
+    /// Allocate zeroed space for a SCM_RIGHTS message carrying one fd.
+    fn single() -> ControlBuf {
+        ControlBuf::new(unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize })
     }
 
-    fn recv_fd(&self) -> Result<RawFd, Error> {
-        let mut dummy: c_int = -1;
-        let msg_len = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as _ };
-        let mut u = HeaderAlignedBuf { buf: [0; 256] };
-        let mut iov = libc::iovec {
-            iov_base: &mut dummy as *mut c_int as *mut c_void,
-            iov_len: mem::size_of_val(&dummy),
+    /// Allocate zeroed space for a single SCM_RIGHTS message carrying `count` fds.
+    fn for_fds(count: usize) -> Result<ControlBuf, Error> {
+        Ok(ControlBuf::new(rights_space(count)?))
+    }
+
+    /// Allocate `len` zeroed bytes, on the stack if they fit.
+    fn new(len: usize) -> ControlBuf {
+        let hdr_size = mem::size_of::<libc::cmsghdr>();
+        let zeroed: libc::cmsghdr = unsafe { mem::zeroed() };
+        let hdrs = len.div_ceil(hdr_size);
+        let storage = if len <= mem::size_of::<SingleFd>() {
+            // only zero what a single fd needs
+            Storage::Single(unsafe { mem::zeroed() })
+        } else if hdrs <= INLINE_HDRS {
+            Storage::Inline([zeroed; INLINE_HDRS])
+        } else {
+            Storage::Heap(vec![zeroed; hdrs])
         };
+        ControlBuf { storage, len }
+    }
 
-        let mut msg: MaybeUninit<msghdr> = MaybeUninit::uninit();
-        unsafe {
-            let msg_ptr = msg.as_mut_ptr();
-            (*msg_ptr).msg_name = std::ptr::null_mut();
-            (*msg_ptr).msg_namelen = 0;
-            (*msg_ptr).msg_iov = &mut iov;
-            (*msg_ptr).msg_iovlen = 1;
-            (*msg_ptr).msg_control = u.buf.as_mut_ptr() as *mut c_void;
-            (*msg_ptr).msg_controllen = msg_len;
-            (*msg_ptr).msg_flags = 0;
+    /// Grow to fit a single SCM_RIGHTS message carrying `count` fds.
+    fn reserve_fds(&mut self, count: usize) -> Result<(), Error> {
+        let len = rights_space(count)?;
+        if len > self.len {
+            *self = ControlBuf::new(len);
         }
-        let mut msg = unsafe { msg.assume_init() };
+        Ok(())
+    }
 
-        unsafe {
-            let rv = libc::recvmsg(*self, &mut msg, 0);
-            match rv {
-                0 => Err(Error::new(ErrorKind::UnexpectedEof, "0 bytes read")),
-                rv if rv < 0 => Err(Error::last_os_error()),
-                _ => {
-                    let hdr: *mut libc::cmsghdr =
-                        if msg.msg_controllen >= mem::size_of::<libc::cmsghdr>() as _ {
-                            msg.msg_control as *mut libc::cmsghdr
-                        } else {
-                            return Err(Error::new(
-                                ErrorKind::InvalidData,
-                                "bad control msg (header)",
-                            ));
-                        };
-                    if (*hdr).cmsg_level != libc::SOL_SOCKET || (*hdr).cmsg_type != libc::SCM_RIGHTS
-                    {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "bad control msg (level)",
-                        ));
-                    }
-                    if msg.msg_controllen != libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as _ {
-                        return Err(Error::new(ErrorKind::InvalidData, "bad control msg (len)"));
-                    }
-                    // https://github.com/rust-lang/rust-clippy/issues/2881
-                    #[allow(clippy::cast_ptr_alignment)]
-                    let fd = std::ptr::read_unaligned(libc::CMSG_DATA(hdr) as *mut c_int);
-                    if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
-                        return Err(Error::last_os_error());
-                    }
-                    Ok(fd)
-                }
-            }
+    fn as_mut_ptr(&mut self) -> *mut c_void {
+        match &mut self.storage {
+            Storage::Single(single) => single as *mut SingleFd as *mut c_void,
+            Storage::Inline(hdrs) => hdrs.as_mut_ptr() as *mut c_void,
+            Storage::Heap(hdrs) => hdrs.as_mut_ptr() as *mut c_void,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn as_mut_bytes(&mut self) -> &mut [u8] {
+        let len = self.len;
+        // storage is zeroed and at least `len` bytes long
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr() as *mut u8, len) }
+    }
+
+    /// Size of the underlying storage in bytes
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        match &self.storage {
+            Storage::Single(single) => mem::size_of_val(single),
+            Storage::Inline(hdrs) => mem::size_of_val(hdrs),
+            Storage::Heap(hdrs) => mem::size_of_val(&hdrs[..]),
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::File;
-    use std::io::Read;
-    use std::os::unix::io::{AsRawFd, FromRawFd};
-    use std::os::unix::net::{UnixListener, UnixStream};
-    use tempdir::TempDir;
+// Kernel sets MSG_CTRUNC when the control buffer was too small. Whatever
+// descriptors did fit are closed, so they don't leak.
+#[cfg(feature = "std")]
+unsafe fn check_truncated(msg: &msghdr) -> Result<(), Error> {
+    if msg.msg_flags & libc::MSG_CTRUNC == 0 {
+        return Ok(());
+    }
+    // Linux also sets it when the descriptor table filled up while
+    // installing the fds, check before closing any of them
+    let full = fd_table_full();
+    let delivered = msg_fds(msg).count();
+    if full {
+        return Err(PassFdError::FdTableFull.into());
+    }
+    Err(PassFdError::Truncated { delivered }.into())
+}
 
-    #[test]
-    fn assert_sized() {
-        let msg_len = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize };
-        let u = HeaderAlignedBuf { buf: [0; 256] };
-        assert!(msg_len < std::mem::size_of_val(&u));
+// The caller owns `fd` from here on. Only such fds are recorded for
+// `check_leaks`: the ones the crate closes itself are not, their numbers
+// may well be reused by the time it runs.
+#[cfg(feature = "std")]
+fn hand_over(fd: OwnedFd) -> OwnedFd {
+    #[cfg(feature = "leak_check")]
+    leaks::track(fd.as_raw_fd());
+    fd
+}
+
+// Close a handed over fd after all, e.g. when a check on it failed
+#[cfg(feature = "std")]
+fn discard(fd: OwnedFd) {
+    #[cfg(feature = "leak_check")]
+    leaks::untrack(fd.as_raw_fd());
+    drop(fd);
+}
+
+// Whether a new descriptor can't be allocated right now, by opening a
+// probe socket. Best effort: other threads opening or closing fds after the
+// receive, or before the probe, change the answer.
+#[cfg(feature = "std")]
+fn fd_table_full() -> bool {
+    let probe = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0) };
+    if probe >= 0 {
+        unsafe { libc::close(probe) };
+        return false;
     }
+    matches!(raw::errno(), libc::EMFILE | libc::ENFILE)
+}
 
-    #[test]
-    fn it_works() {
-        let tmp_dir = TempDir::new("passfd").unwrap();
-        let sock_path = tmp_dir.path().join("listener.sock");
+// CLOCK_MONOTONIC as a Duration, unlike Instant it can be sent to another
+// process
+#[cfg(feature = "std")]
+fn monotonic_now() -> Duration {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    // can't fail with a valid clock and pointer
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
 
-        match unsafe { libc::fork() } {
-            -1 => panic!("fork went wrong"),
-            0 => {
-                println!("child process, wait for socket to appear");
-                for _ in 0..10 {
-                    match UnixStream::connect(sock_path.clone()) {
-                        Ok(stream) => {
-                            println!("stream connected");
-                            let fd = stream.recv_fd().unwrap();
-                            let mut file = unsafe { File::from_raw_fd(fd) };
-                            let mut buf = String::new();
-                            file.read_to_string(&mut buf).unwrap();
-                            return;
-                        }
-                        Err(_) => {
-                            println!("not connected");
-                            std::thread::sleep(std::time::Duration::from_secs(1));
-                        }
-                    }
-                }
-            }
-            _ => {
-                println!("parent, start listening");
-                let listener = UnixListener::bind(sock_path).unwrap();
-                println!("opening file");
-                let file = File::open("/etc/passwd").unwrap();
-                let (stream, _) = listener.accept().unwrap();
-                println!("client connected, sending fd");
-                stream.send_fd(file.as_raw_fd()).unwrap();
+// Fallback for platforms without MSG_CMSG_CLOEXEC. Takes a borrowed fd so
+// the caller already owns it: if fcntl fails, the `?` at the call site
+// drops the OwnedFd and the descriptor isn't leaked.
+#[cfg(feature = "std")]
+fn set_cloexec(fd: BorrowedFd<'_>) -> Result<(), Error> {
+    if !cfg!(feature = "recv_cloexec") || RECV_FLAGS != 0 {
+        return Ok(());
+    }
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// errno of a socket call as io::Error. ENOTSOCK almost always means a pipe
+// or a file was used by mistake, so it gets a message saying so. EPIPE, the
+// peer having closed its end, stays a plain OS error: its kind is already
+// ErrorKind::BrokenPipe, and with MSG_NOSIGNAL/SO_NOSIGPIPE no signal comes
+// with it.
+#[cfg(feature = "std")]
+fn os_error(errno: raw::Errno) -> Error {
+    match errno {
+        libc::ENOTSOCK => PassFdError::NotSocket.into(),
+        // BSDs fail recvmsg when there's no room for the received fds
+        libc::EMFILE | libc::ENFILE => PassFdError::FdTableFull.into(),
+        _ => Error::from_raw_os_error(errno),
+    }
+}
+
+// There's no MSG_NOSIGNAL on Apple platforms, a socket option is used instead
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+fn set_nosigpipe(fd: RawFd) -> Result<(), Error> {
+    let on: c_int = 1;
+    let rv = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            &on as *const c_int as *const c_void,
+            mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if rv < 0 {
+        return Err(os_error(raw::errno()));
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "std", not(any(target_os = "macos", target_os = "ios"))))]
+fn set_nosigpipe(_fd: RawFd) -> Result<(), Error> {
+    Ok(())
+}
+
+// dup2 which also sets FD_CLOEXEC on the copy, atomically where possible
+#[cfg(all(
+    feature = "std",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )
+))]
+fn dup_cloexec(fd: RawFd, target: RawFd) -> Result<(), Error> {
+    if unsafe { libc::dup3(fd, target, libc::O_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    feature = "std",
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))
+))]
+fn dup_cloexec(fd: RawFd, target: RawFd) -> Result<(), Error> {
+    if unsafe { libc::dup2(fd, target) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(target, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Copy of `fd` numbered `min` or above, keeping its FD_CLOEXEC
+#[cfg(feature = "std")]
+fn dup_at_least(fd: BorrowedFd, min: RawFd) -> Result<OwnedFd, Error> {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    if flags < 0 {
+        return Err(Error::last_os_error());
+    }
+    let cmd = if flags & libc::FD_CLOEXEC != 0 {
+        libc::F_DUPFD_CLOEXEC
+    } else {
+        libc::F_DUPFD
+    };
+    let moved = unsafe { libc::fcntl(fd.as_raw_fd(), cmd, min) };
+    if moved < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(moved) })
+}
+
+// recvmsg returns 0 both on EOF and for a message without data. Datagram
+// and seqpacket sockets can carry fds with no data at all, so only a
+// message without control data is EOF.
+#[cfg(feature = "std")]
+fn is_eof(msg: &msghdr, rv: usize) -> bool {
+    rv == 0 && unsafe { libc::CMSG_FIRSTHDR(msg) }.is_null()
+}
+
+// Restart a syscall interrupted by a signal
+#[cfg(feature = "std")]
+fn retry_eintr<F: FnMut() -> libc::ssize_t>(mut f: F) -> libc::ssize_t {
+    loop {
+        let rv = f();
+        if rv < 0 && Error::last_os_error().kind() == ErrorKind::Interrupted {
+            continue;
+        }
+        return rv;
+    }
+}
+
+// Pick the single fd out of a received message. Everything in every header
+// is owned first, so nothing is leaked on error or past the first fd.
+#[cfg(feature = "std")]
+unsafe fn single_fd(msg: &msghdr) -> Result<OwnedFd, Error> {
+    let parsed = parse_control(msg);
+    let mut fds = msg_fds(msg);
+    let first = fds.next();
+    fds.for_each(drop);
+    let parsed = parsed?;
+    first.ok_or_else(|| {
+        if parsed.empty_rights {
+            PassFdError::NoFds.into()
+        } else if parsed.other {
+            PassFdError::WrongCmsgType.into()
+        } else {
+            PassFdError::NoControlMessage.into()
+        }
+    })
+}
+
+// Receive a single fd, reading the data portion of the message into `buf`.
+// FD_CLOEXEC is only set when `cloexec` is true, `flags` go to recvmsg.
+// Returns the fd, number of bytes read and msg_flags.
+#[cfg(feature = "std")]
+fn recv_one(
+    fd: RawFd,
+    buf: &mut [u8],
+    control: &mut [u8],
+    cloexec: bool,
+    flags: c_int,
+    name: Option<&mut UnixAddr>,
+) -> Result<(OwnedFd, usize, c_int), Error> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: MaybeUninit<msghdr> = MaybeUninit::uninit();
+    unsafe {
+        let msg_ptr = msg.as_mut_ptr();
+        (*msg_ptr).msg_name = std::ptr::null_mut();
+        (*msg_ptr).msg_namelen = 0;
+        (*msg_ptr).msg_iov = &mut iov;
+        (*msg_ptr).msg_iovlen = 1;
+        (*msg_ptr).msg_control = control.as_mut_ptr() as *mut c_void;
+        (*msg_ptr).msg_controllen = control.len() as _;
+        (*msg_ptr).msg_flags = 0;
+    }
+    let mut msg = unsafe { msg.assume_init() };
+    if let Some(name) = &name {
+        msg.msg_name = &name.addr as *const libc::sockaddr_un as *mut c_void;
+        msg.msg_namelen = name.len;
+    }
+
+    let flags = if cloexec { flags | RECV_FLAGS } else { flags };
+    let rv = unsafe { raw::recvmsg(fd, &mut msg, flags) }.map_err(os_error)?;
+    if is_eof(&msg, rv) {
+        return Err(PassFdError::UnexpectedEof.into());
+    }
+    if let Some(name) = name {
+        name.len = msg.msg_namelen;
+    }
+    unsafe {
+        check_truncated(&msg)?;
+        let fd = single_fd(&msg)?;
+        if cloexec {
+            set_cloexec(fd.as_fd())?;
+        }
+        Ok((fd, rv, msg.msg_flags))
+    }
+}
+
+// Like recv_one, but a message without any control message isn't an error
+#[cfg(feature = "std")]
+fn recv_opt(
+    fd: RawFd,
+    bufs: &mut [IoSliceMut],
+    control: &mut ControlBuf,
+) -> Result<(Option<OwnedFd>, usize), Error> {
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    // IoSliceMut is ABI compatible with iovec
+    msg.msg_iov = bufs.as_mut_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    msg.msg_control = control.as_mut_ptr();
+    msg.msg_controllen = control.len() as _;
+
+    let rv = unsafe { raw::recvmsg(fd, &mut msg, RECV_FLAGS) }.map_err(os_error)?;
+    if is_eof(&msg, rv) {
+        return Err(PassFdError::UnexpectedEof.into());
+    }
+    unsafe {
+        check_truncated(&msg)?;
+        if libc::CMSG_FIRSTHDR(&msg).is_null() {
+            return Ok((None, rv));
+        }
+        let fd = single_fd(&msg)?;
+        set_cloexec(fd.as_fd())?;
+        Ok((Some(fd), rv))
+    }
+}
+
+// Send a frame of a length prefix and a body with `fd` attached, see
+// `send_message`
+#[cfg(feature = "std")]
+fn send_frame(socket: RawFd, fd: Option<RawFd>, header: &[u8], body: &[u8]) -> Result<(), Error> {
+    // the peer would refuse it anyway
+    if body.len() > MAX_FRAME_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            PassFdError::FrameTooLong {
+                len: body.len() as u64,
+            },
+        ));
+    }
+    send_all(
+        socket,
+        None,
+        fd.as_slice(),
+        &mut [IoSlice::new(header), IoSlice::new(body)],
+        0,
+    )
+}
+
+// Receive a frame of a length prefix and a body, see `recv_message`.
+// `body_len` decodes the prefix, which is checked against MAX_FRAME_LEN.
+#[cfg(feature = "std")]
+fn recv_frame<const N: usize>(
+    socket: RawFd,
+    body_len: impl Fn([u8; N]) -> u64,
+) -> Result<(Option<RawFd>, Vec<u8>), Error> {
+    let checked_len = |header| match body_len(header) {
+        len if len > MAX_FRAME_LEN as u64 => Err(PassFdError::FrameTooLong { len }),
+        len => Ok(len as usize),
+    };
+    let mut header = [0u8; N];
+    let mut control = ControlBuf::single();
+    if socket_type(socket)? == libc::SOCK_STREAM {
+        // an error drops the fd, which closes it
+        let (fd, n) = recv_opt(socket, &mut [IoSliceMut::new(&mut header)], &mut control)?;
+        recv_exact(socket, &mut header[n..])?;
+        let mut body = vec![0u8; checked_len(header)?];
+        recv_exact(socket, &mut body)?;
+        return Ok((fd.map(|fd| hand_over(fd).into_raw_fd()), body));
+    }
+
+    // whole message has to be read at once, so peek at the length first
+    let rv = retry_eintr(|| unsafe {
+        libc::recv(
+            socket,
+            header.as_mut_ptr() as *mut c_void,
+            header.len(),
+            libc::MSG_PEEK,
+        )
+    });
+    match rv {
+        0 => {
+            // an empty message, with or without an fd, has to be taken off
+            // the queue, or every later call would find it again
+            // the fd is closed
+            return match recv_opt(socket, &mut [IoSliceMut::new(&mut header)], &mut control) {
+                Err(err) if err.kind() != ErrorKind::UnexpectedEof => Err(err),
+                // nothing was queued, the peer is gone
+                Err(err) if hung_up(socket)? => Err(err),
+                _ => Err(PassFdError::ShortMessage.into()),
+            };
+        }
+        rv if rv < 0 => return Err(Error::last_os_error()),
+        _ => {}
+    }
+    let len = match checked_len(header) {
+        Ok(len) => len,
+        Err(err) => {
+            // take the message off the queue, closing its fd
+            let _ = recv_opt(socket, &mut [IoSliceMut::new(&mut header)], &mut control);
+            return Err(err.into());
+        }
+    };
+    let mut body = vec![0u8; len];
+    let expected = header.len() + body.len();
+    let mut bufs = [IoSliceMut::new(&mut header), IoSliceMut::new(&mut body)];
+    let (fd, n) = recv_opt(socket, &mut bufs, &mut control)?;
+    if n < expected {
+        return Err(PassFdError::ShortMessage.into());
+    }
+    Ok((fd.map(|fd| hand_over(fd).into_raw_fd()), body))
+}
+
+// Whether the peer of a connected socket went away
+#[cfg(feature = "std")]
+fn hung_up(socket: RawFd) -> Result<bool, Error> {
+    let mut pfd = libc::pollfd {
+        fd: socket,
+        events: 0,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pfd, 1, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(pfd.revents & libc::POLLHUP != 0)
+}
+
+// Fill `buf` with plain reads, for the rest of a frame on a stream socket
+#[cfg(feature = "std")]
+fn recv_exact(fd: RawFd, buf: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let rv = retry_eintr(|| unsafe {
+            libc::recv(fd, rest.as_mut_ptr() as *mut c_void, rest.len(), 0)
+        });
+        match rv {
+            0 => return Err(PassFdError::UnexpectedEof.into()),
+            rv if rv < 0 => return Err(Error::last_os_error()),
+            rv => filled += rv as usize,
+        }
+    }
+    Ok(())
+}
+
+// Streams need a different framing strategy than sockets keeping boundaries
+#[cfg(feature = "std")]
+fn socket_type(fd: RawFd) -> Result<c_int, Error> {
+    let mut ty: c_int = 0;
+    let mut len = mem::size_of_val(&ty) as libc::socklen_t;
+    let rv = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if rv < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(ty)
+}
+
+// Address family of the socket. SO_DOMAIN is Linux-only, elsewhere ask
+// getsockname, which reports the family even for unbound sockets.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+fn socket_domain(fd: RawFd) -> Result<c_int, Error> {
+    let mut domain: c_int = 0;
+    let mut len = mem::size_of_val(&domain) as libc::socklen_t;
+    let rv = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if rv < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(domain)
+}
+
+#[cfg(all(feature = "std", not(any(target_os = "linux", target_os = "android"))))]
+fn socket_domain(fd: RawFd) -> Result<c_int, Error> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    let rv = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if rv < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(addr.ss_family as c_int)
+}
+
+// Send `fds` in a single message, using `control` as the control buffer.
+// It's grown if there's not enough room. Returns number of payload bytes sent.
+#[cfg(feature = "std")]
+fn send_with(
+    socket: RawFd,
+    name: Option<&UnixAddr>,
+    fds: &[RawFd],
+    bufs: &[IoSlice],
+    control: &mut ControlBuf,
+    flags: c_int,
+) -> Result<usize, Error> {
+    control.reserve_fds(fds.len())?;
+    let control_len = unsafe { libc::CMSG_SPACE(mem::size_of_val(fds) as u32) };
+
+    let mut msg: MaybeUninit<msghdr> = MaybeUninit::zeroed();
+    unsafe {
+        let msg_ptr = msg.as_mut_ptr();
+        (*msg_ptr).msg_name = std::ptr::null_mut();
+        (*msg_ptr).msg_namelen = 0;
+        // IoSlice is ABI compatible with iovec
+        (*msg_ptr).msg_iov = bufs.as_ptr() as *mut libc::iovec;
+        (*msg_ptr).msg_iovlen = bufs.len() as _;
+        if fds.is_empty() {
+            (*msg_ptr).msg_control = std::ptr::null_mut();
+            (*msg_ptr).msg_controllen = 0;
+        } else {
+            (*msg_ptr).msg_control = control.as_mut_ptr();
+            (*msg_ptr).msg_controllen = control_len as _;
+        }
+        (*msg_ptr).msg_flags = 0;
+    }
+    let mut msg = unsafe { msg.assume_init() };
+    if let Some(name) = name {
+        msg.msg_name = &name.addr as *const libc::sockaddr_un as *mut c_void;
+        msg.msg_namelen = name.len;
+    }
+
+    if !fds.is_empty() {
+        unsafe {
+            let mut hdr: MaybeUninit<libc::cmsghdr> = MaybeUninit::zeroed();
+            {
+                let hdr = hdr.as_mut_ptr();
+                (*hdr).cmsg_level = libc::SOL_SOCKET;
+                (*hdr).cmsg_type = libc::SCM_RIGHTS;
+                (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as u32) as _;
+            }
+            let hdr = hdr.assume_init();
+            let first = libc::CMSG_FIRSTHDR(&msg);
+            // https://github.com/rust-lang/rust-clippy/issues/2881
+            #[allow(clippy::cast_ptr_alignment)]
+            std::ptr::write_unaligned(first, hdr);
+
+            let data = libc::CMSG_DATA(first) as *mut c_int;
+            for (i, fd) in fds.iter().enumerate() {
+                std::ptr::write_unaligned(data.add(i), *fd);
+            }
+        }
+    }
+
+    set_nosigpipe(socket)?;
+    check_bare_fds(socket, fds, bufs)?;
+    let sent = unsafe { raw::sendmsg(socket, &msg, flags) }.map_err(os_error)?;
+    // there was payload but none of it went out, so it's unknown whether
+    // the fds did
+    if sent == 0 && bufs.iter().any(|buf| !buf.is_empty()) {
+        return Err(Error::new(ErrorKind::WriteZero, "sendmsg wrote 0 bytes"));
+    }
+    Ok(sent)
+}
+
+// A stream socket silently drops control data sent without any payload,
+// so fds would be lost while sendmsg reports success
+#[cfg(feature = "std")]
+fn check_bare_fds(socket: RawFd, fds: &[RawFd], bufs: &[IoSlice]) -> Result<(), Error> {
+    if fds.is_empty() || bufs.iter().any(|buf| !buf.is_empty()) {
+        return Ok(());
+    }
+    if socket_type(socket)? == libc::SOCK_STREAM {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "stream sockets can't pass fds without payload",
+        ));
+    }
+    Ok(())
+}
+
+// Send `fds` with the first chunk of `bufs`, then keep writing until
+// everything is delivered, like `Write::write_all`. Stream sockets may
+// accept a message in pieces.
+#[cfg(feature = "std")]
+fn send_all(
+    socket: RawFd,
+    name: Option<&UnixAddr>,
+    fds: &[RawFd],
+    bufs: &mut [IoSlice],
+    flags: c_int,
+) -> Result<(), Error> {
+    let mut control = ControlBuf::for_fds(fds.len())?;
+    let sent = send_with(socket, name, fds, bufs, &mut control, flags)?;
+    send_rest(socket, name, bufs, sent, !fds.is_empty(), flags)
+}
+
+// Write what's left of `bufs` after the first `sent` bytes went out. When
+// `fds_sent`, the fds are gone already, so WouldBlock must not make the
+// caller retry the whole send and is reported as PartialSend.
+#[cfg(feature = "std")]
+fn send_rest(
+    socket: RawFd,
+    name: Option<&UnixAddr>,
+    mut bufs: &mut [IoSlice],
+    mut sent: usize,
+    fds_sent: bool,
+    flags: c_int,
+) -> Result<(), Error> {
+    let mut control = ControlBuf::single();
+    let mut left: usize = bufs.iter().map(|buf| buf.len()).sum::<usize>() - sent;
+    let mut total = sent;
+    while left > 0 {
+        IoSlice::advance_slices(&mut bufs, sent);
+        sent = send_with(socket, name, &[], bufs, &mut control, flags).map_err(|err| {
+            if err.kind() == ErrorKind::WouldBlock && fds_sent {
+                PassFdError::PartialSend { sent: total }.into()
+            } else {
+                err
+            }
+        })?;
+        total += sent;
+        left -= sent;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+impl FdPassingExt for RawFd {
+    fn send_fds(&self, fds: &[RawFd], payload: &[u8]) -> Result<(), Error> {
+        send_all(*self, None, fds, &mut [IoSlice::new(payload)], 0)
+    }
+
+    fn try_send_fd_with_payload(&self, fd: RawFd, payload: &[u8]) -> Result<usize, Error> {
+        let bufs = [IoSlice::new(payload)];
+        send_with(*self, None, &[fd], &bufs, &mut ControlBuf::single(), 0)
+    }
+
+    fn send_fd_with_flags(&self, fd: RawFd, payload: &[u8], flags: c_int) -> Result<(), Error> {
+        send_all(*self, None, &[fd], &mut [IoSlice::new(payload)], flags)
+    }
+
+    fn send_fd_vectored(&self, fd: RawFd, bufs: &[IoSlice]) -> Result<(), Error> {
+        send_all(*self, None, &[fd], &mut bufs.to_vec(), 0)
+    }
+
+    fn send_fd_to(&self, fd: RawFd, addr: &SocketAddr) -> Result<(), Error> {
+        let name = UnixAddr::from_socket_addr(addr)?;
+        let dummy_payload = [0u8; mem::size_of::<c_int>()];
+        send_all(
+            *self,
+            Some(&name),
+            &[fd],
+            &mut [IoSlice::new(&dummy_payload)],
+            0,
+        )
+    }
+
+    fn recv_fd_from(&self) -> Result<(RawFd, SocketAddr), Error> {
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        let mut name = UnixAddr::new();
+        let (fd, _, _) = recv_one(
+            *self,
+            &mut dummy,
+            ControlBuf::single().as_mut_bytes(),
+            true,
+            0,
+            Some(&mut name),
+        )?;
+        // fd is still owned here, so a bad address doesn't leak it
+        let addr = name.to_socket_addr()?;
+        Ok((hand_over(fd).into_raw_fd(), addr))
+    }
+
+    fn send_message(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error> {
+        let len = u32::try_from(body.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "message body too long"))?;
+        send_frame(*self, fd, &len.to_ne_bytes(), body)
+    }
+
+    fn send_fd_framed(&self, fd: Option<RawFd>, body: &[u8]) -> Result<(), Error> {
+        send_frame(*self, fd, &(body.len() as u64).to_be_bytes(), body)
+    }
+
+    fn finish_sending(&self) -> Result<(), Error> {
+        if unsafe { libc::shutdown(*self, libc::SHUT_WR) } < 0 {
+            return Err(os_error(raw::errno()));
+        }
+        Ok(())
+    }
+
+    fn send_fd_with_ack(&self, fd: RawFd) -> Result<(), Error> {
+        self.send_fd(fd)?;
+        let mut ack = 0u8;
+        let rv =
+            retry_eintr(|| unsafe { libc::recv(*self, &mut ack as *mut u8 as *mut c_void, 1, 0) });
+        match rv {
+            0 => Err(PassFdError::UnexpectedEof.into()),
+            rv if rv < 0 => Err(Error::last_os_error()),
+            _ if ack != ACK => Err(PassFdError::BadAck.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn send_fd_all(&self, fd: RawFd, payload: &[u8]) -> Result<(), Error> {
+        send_all(*self, None, &[fd], &mut [IoSlice::new(payload)], 0)
+    }
+
+    fn send_fd_blocking_once(&self, fd: RawFd) -> Result<(), Error> {
+        let dummy_payload = [0u8; mem::size_of::<c_int>()];
+        let mut control = ControlBuf::single();
+        let mut fds: &[RawFd] = &[fd];
+        let mut sent = 0;
+        // O_NONBLOCK belongs to the open socket, wait with poll instead
+        let mut pfd = libc::pollfd {
+            fd: *self,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        while !fds.is_empty() || sent < dummy_payload.len() {
+            let bufs = [IoSlice::new(&dummy_payload[sent..])];
+            match send_with(*self, None, fds, &bufs, &mut control, 0) {
+                Ok(n) => {
+                    fds = &[];
+                    sent += n;
+                }
+                // POLLHUP and POLLERR return too, the next send fails then
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                        let err = Error::last_os_error();
+                        if err.kind() != ErrorKind::Interrupted {
+                            return Err(err);
+                        }
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_message(&self) -> Result<(Option<RawFd>, Vec<u8>), Error> {
+        recv_frame(*self, |header: [u8; 4]| u32::from_ne_bytes(header).into())
+    }
+
+    fn recv_fd_framed(&self) -> Result<(Option<RawFd>, Vec<u8>), Error> {
+        recv_frame(*self, u64::from_be_bytes)
+    }
+
+    fn recv_owned_fd(&self) -> Result<OwnedFd, Error> {
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        recv_one(
+            *self,
+            &mut dummy,
+            ControlBuf::single().as_mut_bytes(),
+            true,
+            0,
+            None,
+        )
+        .map(|(fd, _, _)| hand_over(fd))
+    }
+
+    fn recv_fd_nonblocking(&self) -> Result<RawFd, Error> {
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        let mut control = ControlBuf::single();
+        recv_one(
+            *self,
+            &mut dummy,
+            control.as_mut_bytes(),
+            true,
+            libc::MSG_DONTWAIT,
+            None,
+        )
+        .map(|(fd, _, _)| hand_over(fd).into_raw_fd())
+    }
+
+    fn peek_fd_available(&self) -> Result<bool, Error> {
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        // non-null but empty: FreeBSD only reports control data it was
+        // given somewhere to put
+        let mut control = ControlBuf::single();
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = 0;
+        let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+        match unsafe { raw::recvmsg(*self, &mut msg, flags) } {
+            Ok(0) if msg.msg_flags & libc::MSG_CTRUNC == 0 => {
+                // bare datagrams are empty too
+                if socket_type(*self)? == libc::SOCK_STREAM {
+                    return Err(PassFdError::UnexpectedEof.into());
+                }
+                Ok(false)
+            }
+            Ok(_) => Ok(msg.msg_flags & libc::MSG_CTRUNC != 0),
+            Err(libc::EAGAIN) => Ok(false),
+            Err(errno) => Err(os_error(errno)),
+        }
+    }
+
+    fn recv_fd_with_payload(&self, buf: &mut [u8]) -> Result<(RawFd, usize), Error> {
+        recv_one(
+            *self,
+            buf,
+            ControlBuf::single().as_mut_bytes(),
+            true,
+            0,
+            None,
+        )
+        .map(|(fd, n, _)| (hand_over(fd).into_raw_fd(), n))
+    }
+
+    fn recv_fd_with_buffer(&self, cmsg_buf: &mut [u8]) -> Result<RawFd, Error> {
+        let needed = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize };
+        if cmsg_buf.len() < needed {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "control buffer too small",
+            ));
+        }
+        if cmsg_buf
+            .as_ptr()
+            .align_offset(mem::align_of::<libc::cmsghdr>())
+            != 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "control buffer not aligned",
+            ));
+        }
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        recv_one(*self, &mut dummy, cmsg_buf, true, 0, None)
+            .map(|(fd, _, _)| hand_over(fd).into_raw_fd())
+    }
+
+    fn recv_fd_detailed(&self) -> Result<(RawFd, c_int), Error> {
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        recv_one(
+            *self,
+            &mut dummy,
+            ControlBuf::single().as_mut_bytes(),
+            true,
+            0,
+            None,
+        )
+        .map(|(fd, _, flags)| (hand_over(fd).into_raw_fd(), flags))
+    }
+
+    fn recv_fd_waitall(&self, payload: &mut [u8]) -> Result<RawFd, Error> {
+        let (fd, n, _) = recv_one(
+            *self,
+            payload,
+            ControlBuf::single().as_mut_bytes(),
+            true,
+            libc::MSG_WAITALL,
+            None,
+        )?;
+        if n < payload.len() {
+            if socket_type(*self)? != libc::SOCK_STREAM {
+                return Err(PassFdError::ShortPayload.into());
+            }
+            recv_exact(*self, &mut payload[n..])?;
+        }
+        Ok(hand_over(fd).into_raw_fd())
+    }
+
+    fn recv_fd_raw(&self) -> Result<RawFd, Error> {
+        let mut dummy = [0u8; mem::size_of::<c_int>()];
+        recv_one(
+            *self,
+            &mut dummy,
+            ControlBuf::single().as_mut_bytes(),
+            false,
+            0,
+            None,
+        )
+        .map(|(fd, _, _)| hand_over(fd).into_raw_fd())
+    }
+
+    fn recv_fd_timeout(&self, timeout: Duration) -> Result<RawFd, Error> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_fd_deadline(deadline),
+            // too far away to ever expire
+            None => self.recv_fd(),
+        }
+    }
+
+    fn recv_fd_deadline(&self, deadline: Instant) -> Result<RawFd, Error> {
+        // poll leaves the socket's blocking/timeout state alone
+        let mut pfd = libc::pollfd {
+            fd: *self,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let millis = deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(c_int::MAX as u128) as c_int;
+            match unsafe { libc::poll(&mut pfd, 1, millis) } {
+                0 => return Err(PassFdError::TimedOut.into()),
+                rv if rv < 0 => {
+                    let err = Error::last_os_error();
+                    if err.kind() != ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                // another reader may have taken the message; POLLHUP and
+                // POLLERR fail right away
+                _ => match self.recv_fd_nonblocking() {
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    result => return result,
+                },
+            }
+        }
+    }
+
+    fn recv_fds_counted(&self, max: usize) -> Result<(Vec<RawFd>, usize), Error> {
+        let mut dummy: c_int = -1;
+        let mut control = ControlBuf::for_fds(max)?;
+        let mut iov = libc::iovec {
+            iov_base: &mut dummy as *mut c_int as *mut c_void,
+            iov_len: mem::size_of_val(&dummy),
+        };
+
+        let mut msg: MaybeUninit<msghdr> = MaybeUninit::zeroed();
+        unsafe {
+            let msg_ptr = msg.as_mut_ptr();
+            (*msg_ptr).msg_name = std::ptr::null_mut();
+            (*msg_ptr).msg_namelen = 0;
+            (*msg_ptr).msg_iov = &mut iov;
+            (*msg_ptr).msg_iovlen = 1;
+            (*msg_ptr).msg_control = control.as_mut_ptr();
+            (*msg_ptr).msg_controllen = control.len() as _;
+            (*msg_ptr).msg_flags = 0;
+        }
+        let mut msg = unsafe { msg.assume_init() };
+
+        let rv = unsafe { raw::recvmsg(*self, &mut msg, RECV_FLAGS) }.map_err(os_error)?;
+        if is_eof(&msg, rv) {
+            return Err(PassFdError::UnexpectedEof.into());
+        }
+        unsafe { check_truncated(&msg)? };
+
+        let mut fds: Vec<OwnedFd> = unsafe { msg_fds(&msg).collect() };
+        if fds.is_empty() {
+            return Err(PassFdError::NoControlMessage.into());
+        }
+        // control buffer may have room for more than asked because of
+        // padding; drop closes the extra ones
+        let count = fds.len();
+        fds.truncate(max);
+        for fd in &fds {
+            set_cloexec(fd.as_fd())?;
+        }
+        Ok((
+            fds.into_iter()
+                .map(|fd| hand_over(fd).into_raw_fd())
+                .collect(),
+            count,
+        ))
+    }
+
+    fn recv_fds_into(&self, out: &mut [RawFd]) -> Result<usize, Error> {
+        let mut dummy: c_int = -1;
+        let mut control = ControlBuf::for_fds(out.len())?;
+        let mut iov = libc::iovec {
+            iov_base: &mut dummy as *mut c_int as *mut c_void,
+            iov_len: mem::size_of_val(&dummy),
+        };
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+
+        let rv = unsafe { raw::recvmsg(*self, &mut msg, RECV_FLAGS) }.map_err(os_error)?;
+        if is_eof(&msg, rv) {
+            return Err(PassFdError::UnexpectedEof.into());
+        }
+        unsafe { check_truncated(&msg)? };
+
+        // padding may leave room for more than asked; drop closes those
+        let mut fds = unsafe { msg_fds(&msg) };
+        let mut stored = 0;
+        for (slot, fd) in out.iter_mut().zip(&mut fds) {
+            *slot = fd.into_raw_fd();
+            stored += 1;
+        }
+        fds.for_each(drop);
+        if stored == 0 {
+            return Err(PassFdError::NoControlMessage.into());
+        }
+        for fd in &out[..stored] {
+            if let Err(err) = set_cloexec(unsafe { BorrowedFd::borrow_raw(*fd) }) {
+                for fd in &out[..stored] {
+                    drop(unsafe { OwnedFd::from_raw_fd(*fd) });
+                }
+                return Err(err);
+            }
+        }
+        #[cfg(feature = "leak_check")]
+        out[..stored].iter().for_each(|fd| leaks::track(*fd));
+        Ok(stored)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_fd_with_creds(&self, fd: RawFd, creds: UCred) -> Result<(), Error> {
+        let ucred = libc::ucred {
+            pid: creds.pid.unwrap_or_else(|| unsafe { libc::getpid() }),
+            uid: creds.uid,
+            gid: creds.gid,
+        };
+        let fds_len = mem::size_of::<c_int>() as u32;
+        let creds_len = mem::size_of_val(&ucred) as u32;
+        let mut control = ControlBuf::new(unsafe {
+            (libc::CMSG_SPACE(fds_len) + libc::CMSG_SPACE(creds_len)) as usize
+        });
+        let dummy_payload = [0u8; mem::size_of::<c_int>()];
+        let mut iov = libc::iovec {
+            iov_base: dummy_payload.as_ptr() as *mut c_void,
+            iov_len: dummy_payload.len(),
+        };
+
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+        // headers follow each other, each padded to alignment (See cmsg(3))
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_RIGHTS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(hdr) as *mut c_int, fd);
+
+            let hdr = libc::CMSG_NXTHDR(&msg, hdr);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_CREDENTIALS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(creds_len) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(hdr) as *mut libc::ucred, ucred);
+        }
+
+        let sent = unsafe { raw::sendmsg(*self, &msg, 0) }.map_err(os_error)?;
+        if sent == 0 {
+            return Err(Error::new(ErrorKind::WriteZero, "sendmsg wrote 0 bytes"));
+        }
+        send_rest(
+            *self,
+            None,
+            &mut [IoSlice::new(&dummy_payload)],
+            sent,
+            true,
+            0,
+        )
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_fd_with_creds(&self) -> Result<(RawFd, UCred), Error> {
+        let on: c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                *self,
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const c_int as *const c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if rv < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut dummy: c_int = -1;
+        let mut control = unsafe {
+            ControlBuf::new(
+                libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize
+                    + libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as u32) as usize,
+            )
+        };
+        let mut iov = libc::iovec {
+            iov_base: &mut dummy as *mut c_int as *mut c_void,
+            iov_len: mem::size_of_val(&dummy),
+        };
+
+        let mut msg: MaybeUninit<msghdr> = MaybeUninit::zeroed();
+        unsafe {
+            let msg_ptr = msg.as_mut_ptr();
+            (*msg_ptr).msg_name = std::ptr::null_mut();
+            (*msg_ptr).msg_namelen = 0;
+            (*msg_ptr).msg_iov = &mut iov;
+            (*msg_ptr).msg_iovlen = 1;
+            (*msg_ptr).msg_control = control.as_mut_ptr();
+            (*msg_ptr).msg_controllen = control.len() as _;
+            (*msg_ptr).msg_flags = 0;
+        }
+        let mut msg = unsafe { msg.assume_init() };
+
+        let rv = unsafe { raw::recvmsg(*self, &mut msg, RECV_FLAGS) }.map_err(os_error)?;
+        if is_eof(&msg, rv) {
+            return Err(PassFdError::UnexpectedEof.into());
+        }
+        unsafe { check_truncated(&msg)? };
+
+        // both SCM_RIGHTS and SCM_CREDENTIALS can arrive, in any order;
+        // all fds are owned first, so they're closed on error
+        let parsed = unsafe { parse_control(&msg) };
+        let fd = unsafe { msg_fds(&msg).reduce(|first, _| first) };
+        let parsed = parsed?;
+        let fd = fd.ok_or(PassFdError::NoControlMessage)?;
+        let creds = parsed.creds.ok_or(PassFdError::NoCredentials)?;
+        set_cloexec(fd.as_fd())?;
+        Ok((hand_over(fd).into_raw_fd(), creds))
+    }
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "haiku"))]
+    fn peer_cred(&self) -> Result<UCred, Error> {
+        let mut ucred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&ucred) as libc::socklen_t;
+        let rv = unsafe {
+            libc::getsockopt(
+                *self,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut ucred as *mut libc::ucred as *mut c_void,
+                &mut len,
+            )
+        };
+        if rv < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(UCred {
+            pid: Some(ucred.pid),
+            uid: ucred.uid,
+            gid: ucred.gid,
+        })
+    }
+
+    #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+    fn peer_cred(&self) -> Result<UCred, Error> {
+        let mut ucred: *mut libc::ucred_t = std::ptr::null_mut();
+        if unsafe { libc::getpeerucred(*self, &mut ucred) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        let cred = unsafe {
+            UCred {
+                pid: Some(libc::ucred_getpid(ucred)),
+                uid: libc::ucred_geteuid(ucred),
+                gid: libc::ucred_getegid(ucred),
+            }
+        };
+        unsafe { libc::ucred_free(ucred) };
+        Ok(cred)
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "haiku",
+        target_os = "illumos",
+        target_os = "solaris"
+    )))]
+    fn peer_cred(&self) -> Result<UCred, Error> {
+        let mut uid: libc::uid_t = 0;
+        let mut gid: libc::gid_t = 0;
+        if unsafe { libc::getpeereid(*self, &mut uid, &mut gid) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(UCred {
+            pid: None,
+            uid,
+            gid,
+        })
+    }
+
+    fn check_fd_passing(&self) -> Result<(), Error> {
+        let not_unix = |err: Error| match err.raw_os_error() {
+            Some(libc::ENOTSOCK) => PassFdError::NotSocket.into(),
+            _ => err,
+        };
+        if socket_domain(*self).map_err(not_unix)? != libc::AF_UNIX {
+            return Err(PassFdError::NotUnixSocket.into());
+        }
+        match socket_type(*self).map_err(not_unix)? {
+            libc::SOCK_STREAM | libc::SOCK_DGRAM | libc::SOCK_SEQPACKET => Ok(()),
+            _ => Err(PassFdError::NotUnixSocket.into()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+
+    // how many fds a control buffer for `max` of them holds, padding included
+    fn fds_fitting(max: usize) -> usize {
+        let header = unsafe { libc::CMSG_LEN(0) } as usize;
+        (rights_space(max).unwrap() - header) / mem::size_of::<c_int>()
+    }
+
+    // device and inode of an open fd, to check a received one is the sent file
+    fn file_id(fd: RawFd) -> (libc::dev_t, libc::ino_t) {
+        let mut st = MaybeUninit::<libc::stat>::uninit();
+        assert_eq!(unsafe { libc::fstat(fd, st.as_mut_ptr()) }, 0);
+        let st = unsafe { st.assume_init() };
+        (st.st_dev, st.st_ino)
+    }
+
+    #[test]
+    fn assert_sized() {
+        for count in [0, 1, 2, 7, 8, 64, 253] {
+            let msg_len =
+                unsafe { libc::CMSG_SPACE((mem::size_of::<c_int>() * count) as u32) as usize };
+            let mut u = ControlBuf::for_fds(count).unwrap();
+            assert_eq!(u.len(), msg_len);
+            assert!(msg_len <= u.capacity());
+            assert_eq!(
+                u.as_mut_ptr() as usize % mem::align_of::<libc::cmsghdr>(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        let (parent, child) = UnixStream::pair().unwrap();
+
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork went wrong"),
+            0 => {
+                drop(parent);
+                let ok = child
+                    .recv_file()
+                    .and_then(|mut file| file.read(&mut [0u8; 16]))
+                    .is_ok_and(|n| n > 0);
+                // don't return into the test harness
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            pid => {
+                drop(child);
+                let file = File::open("/etc/passwd").unwrap();
+                parent.send_fd(file.as_raw_fd()).unwrap();
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn fd_table_full() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork went wrong"),
+            0 => {
+                let limit = libc::rlimit {
+                    rlim_cur: 64,
+                    rlim_max: 64,
+                };
+                unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+                while unsafe { libc::dup(rx.as_raw_fd()) } >= 0 {}
+                let ok = rx
+                    .recv_fd()
+                    .is_err_and(|err| matches!(PassFdError::from(err), PassFdError::FdTableFull));
+                // don't return into the test harness
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn directory_fd_works() {
+        use std::ffi::CString;
+
+        let dir = std::env::temp_dir().join(format!("passfd-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"inside").unwrap();
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        let fd = unsafe { libc::open(path.as_ptr(), flags) };
+        assert!(fd >= 0);
+        let dir_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.send_borrowed_fd(&dir_fd).unwrap();
+        let received = rx.recv_owned_fd().unwrap();
+        drop(dir_fd);
+
+        let name = CString::new("file").unwrap();
+        let fd = unsafe { libc::openat(received.as_raw_fd(), name.as_ptr(), libc::O_RDONLY) };
+        assert!(fd >= 0);
+        let mut contents = String::new();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "inside");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn eventfd_semaphore_works() {
+        let flags = libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK | libc::EFD_CLOEXEC;
+        let fd = unsafe { libc::eventfd(0, flags) };
+        assert!(fd >= 0);
+        let event = unsafe { File::from_raw_fd(fd) };
+        (&event).write_all(&3u64.to_ne_bytes()).unwrap();
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.send_fd(event.as_raw_fd()).unwrap();
+        let received = unsafe { File::from_raw_fd(rx.recv_fd().unwrap()) };
+        drop(event);
+
+        // status flags belong to the open file, so they come along
+        let fl = unsafe { libc::fcntl(received.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(fl & libc::O_NONBLOCK, libc::O_NONBLOCK);
+        // semaphore reads take one at a time until the counter is used up
+        let mut buf = [0u8; 8];
+        for _ in 0..3 {
+            (&received).read_exact(&mut buf).unwrap();
+            assert_eq!(u64::from_ne_bytes(buf), 1);
+        }
+        let err = (&received).read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn finish_sending_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        tx.finish_sending().unwrap();
+
+        let fd = rx.recv_fd_opt().unwrap().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert!(rx.recv_fd_opt().unwrap().is_none());
+
+        // the other direction still works
+        rx.send_fd(file.as_raw_fd()).unwrap();
+        drop(tx.recv_owned_fd().unwrap());
+    }
+
+    #[test]
+    fn concurrent_send_works() {
+        const THREADS: usize = 16;
+        const PER_THREAD: usize = 64;
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        std::thread::scope(|scope| {
+            for i in 0..THREADS {
+                let (tx, file) = (&tx, &file);
+                scope.spawn(move || {
+                    for _ in 0..PER_THREAD {
+                        let tag = (i as u32).to_ne_bytes();
+                        tx.send_fd_with_payload(file.as_raw_fd(), &tag).unwrap();
+                    }
+                });
+            }
+            let mut seen = [0; THREADS];
+            for _ in 0..THREADS * PER_THREAD {
+                let mut tag = [0u8; 4];
+                let fd = rx.recv_fd_expect_payload(&mut tag).unwrap();
+                drop(unsafe { File::from_raw_fd(fd) });
+                seen[u32::from_ne_bytes(tag) as usize] += 1;
+            }
+            assert!(seen.iter().all(|n| *n == PER_THREAD));
+        });
+    }
+
+    #[test]
+    fn recv_fd_waitall_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let sender = std::thread::spawn(move || {
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd_with_payload(file.as_raw_fd(), b"head").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            (&tx).write_all(b"tail").unwrap();
+        });
+        let mut payload = [0u8; 8];
+        let fd = rx.recv_fd_waitall(&mut payload).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert_eq!(&payload, b"headtail");
+        sender.join().unwrap();
+
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_with_payload(file.as_raw_fd(), b"head").unwrap();
+        let err = rx.recv_fd_waitall(&mut payload).unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::ShortPayload));
+    }
+
+    #[test]
+    fn recv_fd_detailed_works() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_with_payload(file.as_raw_fd(), b"longer than four bytes")
+            .unwrap();
+        let (fd, flags) = rx.recv_fd_detailed().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert_eq!(flags & libc::MSG_TRUNC, libc::MSG_TRUNC);
+
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let (fd, flags) = rx.recv_fd_detailed().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert_eq!(flags & libc::MSG_TRUNC, 0);
+    }
+
+    #[cfg(feature = "recv_cloexec")]
+    #[test]
+    fn socketpair_round_trip() {
+        let mut fds = [-1; 2];
+        let rv = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(rv, 0);
+        let (tx, rx) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let file = File::open("/etc/passwd").unwrap();
+        tx.as_raw_fd()
+            .send_fd_with_payload(file.as_raw_fd(), b"data")
+            .unwrap();
+        let mut buf = [0u8; 4];
+        let (fd, n) = rx.as_raw_fd().recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"data");
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn pair_for_fd_passing_works() {
+        let (tx, rx) = pair_for_fd_passing().unwrap();
+        for sock in [&tx, &rx] {
+            let flags = unsafe { libc::fcntl(sock.as_raw_fd(), libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        drop(rx.recv_owned_fd().unwrap());
+    }
+
+    #[test]
+    fn io_safe_fds_work() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (tx, rx) = (OwnedFd::from(tx), OwnedFd::from(rx));
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_borrowed_fd(&file).unwrap();
+        drop(rx.as_fd().recv_owned_fd().unwrap());
+        tx.as_fd().send_fd(file.as_raw_fd()).unwrap();
+        drop(rx.recv_file().unwrap());
+    }
+
+    #[test]
+    fn recv_fd_into_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let target = File::open("/dev/null").unwrap().into_raw_fd();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        rx.recv_fd_into(target).unwrap();
+        let flags = unsafe { libc::fcntl(target, libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        let mut file = unsafe { File::from_raw_fd(target) };
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+
+    #[test]
+    fn recv_owned_fd_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_owned_fd().unwrap();
+        let mut file = File::from(fd);
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+
+    #[test]
+    fn send_fd_after_flush_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let mut writer = BufWriter::new(tx);
+        writer.write_all(b"before").unwrap();
+        send_fd_after_flush(&mut writer, file.as_raw_fd()).unwrap();
+
+        let mut buf = [0u8; 6];
+        (&rx).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"before");
+        drop(rx.recv_owned_fd().unwrap());
+    }
+
+    #[test]
+    fn recv_socket_types() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        tx.send_borrowed_fd(&client).unwrap();
+        let mut received = rx.recv_tcp_stream().unwrap();
+        received.write_all(b"tcp").unwrap();
+        let mut buf = [0u8; 3];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"tcp");
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        tx.send_borrowed_fd(&udp).unwrap();
+        let received = rx.recv_udp_socket().unwrap();
+        assert_eq!(received.local_addr().unwrap(), udp.local_addr().unwrap());
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        tx.send_borrowed_fd(&a).unwrap();
+        let mut received = rx.recv_unix_stream().unwrap();
+        received.write_all(b"unix").unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"unix");
+    }
+
+    #[test]
+    fn recv_file_and_stream() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let mut file = rx.recv_file().unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        tx.send_fd(a.as_raw_fd()).unwrap();
+        drop(a);
+        let mut a = rx.recv_stream().unwrap();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn try_recv_fd_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        assert_eq!(rx.try_recv_fd().unwrap(), None);
+
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.try_recv_fd().unwrap().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_opt_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        drop(tx);
+        let mut count = 0;
+        while let Some(fd) = rx.recv_fd_opt().unwrap() {
+            drop(unsafe { File::from_raw_fd(fd) });
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn check_fd_passing_works() {
+        let (tx, _rx) = UnixStream::pair().unwrap();
+        assert!(tx.supports_fd_passing());
+        let (tx, _rx) = UnixDatagram::pair().unwrap();
+        assert!(tx.supports_fd_passing());
+
+        let file = File::open("/etc/passwd").unwrap();
+        let err = file.as_raw_fd().check_fd_passing().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(PassFdError::from(err), PassFdError::NotSocket));
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = udp.as_raw_fd().check_fd_passing().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::NotUnixSocket));
+        assert!(!udp.as_raw_fd().supports_fd_passing());
+    }
+
+    #[test]
+    fn message_builder_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let fds = [file.as_raw_fd(), file.as_raw_fd()];
+        MessageBuilder::new()
+            .fds(&fds)
+            .payload(b"two")
+            .send(&tx)
+            .unwrap();
+        let mut msg = ReceivedMessage::recv(&rx, 2, 16).unwrap();
+        assert_eq!(msg.payload(), b"two");
+        assert_eq!(msg.fds().len(), 2);
+        drop(msg.fds().take(0).unwrap());
+
+        // no fds at all is fine
+        MessageBuilder::new().payload(b"none").send(&tx).unwrap();
+        let msg = ReceivedMessage::recv(&rx, 2, 16).unwrap();
+        assert_eq!(msg.payload(), b"none");
+        assert!(msg.into_fds().is_empty());
+
+        MessageBuilder::new()
+            .fds(&fds)
+            .payload(b"x")
+            .send(&tx)
+            .unwrap();
+        let err = ReceivedMessage::recv(&rx, 1, 16).unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::TooManyFds));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn message_builder_creds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let on: c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                rx.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const c_int as *const c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        assert_eq!(rv, 0);
+        let file = File::open("/etc/passwd").unwrap();
+        let creds = UCred {
+            pid: None,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        MessageBuilder::new()
+            .fds(&[file.as_raw_fd()])
+            .payload(b"c")
+            .creds(creds)
+            .send(&tx)
+            .unwrap();
+        let mut msg = ReceivedMessage::recv(&rx, 1, 1).unwrap();
+        let got = msg.creds().unwrap();
+        assert_eq!(got.pid, Some(std::process::id() as libc::pid_t));
+        assert_eq!(got.uid, creds.uid);
+        drop(msg.fds().take(0).unwrap());
+    }
+
+    #[test]
+    fn recv_fd_with_payload_bare() {
+        let file = File::open("/etc/passwd").unwrap();
+        let mut buf = [0u8; 8];
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        tx.send_fd_with_payload(file.as_raw_fd(), b"").unwrap();
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(n, 0);
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        // a stream would drop the fd, so it isn't sent at all
+        let (tx, _rx) = UnixStream::pair().unwrap();
+        let err = tx.send_fd_with_payload(file.as_raw_fd(), b"").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn send_fd_with_payload_writes_everything() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        // far more than the socket buffer, so it can't go in one sendmsg
+        let payload: Vec<u8> = (0..8 << 20).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let sender = std::thread::spawn(move || {
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd_with_payload(file.as_raw_fd(), &payload).unwrap();
+        });
+        let mut buf = vec![0u8; expected.len()];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert!(n < buf.len());
+        drop(unsafe { File::from_raw_fd(fd) });
+        (&rx).read_exact(&mut buf[n..]).unwrap();
+        assert!(buf == expected);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn send_fd_with_meta_works() {
+        #[repr(C)]
+        #[derive(Debug, PartialEq)]
+        struct Meta {
+            offset: u64,
+            flags: u32,
+            mode: u32,
+        }
+        unsafe impl AsBytes for Meta {}
+        unsafe impl FromBytes for Meta {}
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let meta = Meta {
+            offset: 42,
+            flags: libc::O_RDONLY as u32,
+            mode: 0o644,
+        };
+        tx.send_fd_with(file.as_raw_fd(), &meta).unwrap();
+        let (fd, got) = rx.recv_fd_with::<Meta>().unwrap();
+        assert_eq!(got, meta);
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        tx.send_fd_with(file.as_raw_fd(), &7u32).unwrap();
+        let err = rx.recv_fd_with::<Meta>().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::ShortPayload));
+    }
+
+    #[test]
+    fn aligned_cmsg_buf_works() {
+        const SIZE: usize = control_buffer_size(1);
+        assert_eq!(SIZE, aligned_control_buffer(1).len());
+        assert_eq!(
+            control_buffer_size(usize::MAX),
+            control_buffer_size(MAX_FDS_PER_MESSAGE)
+        );
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let mut buf = AlignedCmsgBuf::<SIZE>::new();
+        assert_eq!(buf.as_ptr() as usize % mem::align_of::<libc::cmsghdr>(), 0);
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_fd_with_buffer(&mut buf).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_with_buffer_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let mut buf = aligned_control_buffer(1);
+        for _ in 0..2 {
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd(file.as_raw_fd()).unwrap();
+            let fd = rx.recv_fd_with_buffer(&mut buf).unwrap();
+            drop(unsafe { File::from_raw_fd(fd) });
+        }
+
+        let err = rx.recv_fd_with_buffer(&mut buf[..1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let mut bytes = [0u8; 64];
+        let err = rx.recv_fd_with_buffer(&mut bytes[1..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn not_a_socket() {
+        let mut pipe = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let (rx, tx) = unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+        let file = File::open("/etc/passwd").unwrap();
+
+        let err = tx.send_fd(file.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(PassFdError::from(err), PassFdError::NotSocket));
+        let err = rx.recv_fd().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::NotSocket));
+    }
+
+    #[test]
+    fn incoming_fds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        for _ in 0..3 {
+            tx.send_fd(file.as_raw_fd()).unwrap();
+        }
+        let fds = rx.incoming_fds().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(fds.len(), 3);
+        for fd in fds {
+            drop(unsafe { File::from_raw_fd(fd) });
+        }
+        assert!(rx.incoming_fds().next().is_none());
+
+        // peer gone, the error ends the iteration
+        drop(tx);
+        let mut incoming = rx.incoming_fds();
+        let err = incoming.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(incoming.next().is_none());
+    }
+
+    #[test]
+    fn for_each_fd_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        for _ in 0..3 {
+            tx.send_fd(file.as_raw_fd()).unwrap();
+        }
+        drop(tx);
+        let mut count = 0;
+        rx.for_each_fd(|fd| {
+            drop(unsafe { File::from_raw_fd(fd) });
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+
+        // closure errors stop the loop and come back unchanged
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let err = rx
+            .for_each_fd(|fd| {
+                drop(unsafe { File::from_raw_fd(fd) });
+                Err(Error::other("stop"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        drop(unsafe { File::from_raw_fd(rx.recv_fd().unwrap()) });
+
+        // so do receive errors
+        let err = file.as_raw_fd().for_each_fd(|_| Ok(())).unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::NotSocket));
+    }
+
+    #[test]
+    fn nonblocking_works() {
+        // blocking sockets, the calls must not block anyway
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let err = rx.recv_fd_nonblocking().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_nonblocking(file.as_raw_fd()).unwrap();
+        drop(unsafe { File::from_raw_fd(rx.recv_fd_nonblocking().unwrap()) });
+    }
+
+    #[test]
+    fn recv_fd_with_payload_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_with_payload(file.as_raw_fd(), b"tag!").unwrap();
+        let mut buf = [0u8; 4];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"tag!");
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_expect_payload_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let mut tag = [0u8; 4];
+
+        tx.send_fd_with_payload(file.as_raw_fd(), b"TAG1").unwrap();
+        let fd = rx.recv_fd_expect_payload(&mut tag).unwrap();
+        assert_eq!(&tag, b"TAG1");
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        tx.send_fd_with_payload(file.as_raw_fd(), b"T").unwrap();
+        let err = rx.recv_fd_expect_payload(&mut tag).unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::ShortPayload));
+    }
+
+    #[cfg(feature = "recv_cloexec")]
+    #[test]
+    fn recv_fd_sets_cloexec() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_owned_fd().unwrap();
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    // fds are close-on-exec from the moment they arrive: a child exec'd by
+    // another thread in the middle of a receive loop never inherits one
+    #[cfg(all(
+        feature = "recv_cloexec",
+        any(target_os = "linux", target_os = "android")
+    ))]
+    #[test]
+    fn recv_fd_not_inherited_by_exec() {
+        let path = std::env::temp_dir().join(format!("passfd-exec-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let spawner = std::thread::spawn(|| {
+            (0..100)
+                .map(|_| {
+                    let out = std::process::Command::new("/bin/sh")
+                        .args(["-c", "ls -l /proc/self/fd"])
+                        .output()
+                        .unwrap();
+                    String::from_utf8(out.stdout).unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
+        while !spawner.is_finished() {
+            tx.send_fd(file.as_raw_fd()).unwrap();
+            drop(rx.recv_owned_fd().unwrap());
+        }
+        let listings = spawner.join().unwrap();
+        // the file is unlinked, so only fds of this test point to it
+        let name = path.to_str().unwrap();
+        for listing in listings {
+            assert!(!listing.contains(name), "{}", listing);
+        }
+    }
+
+    #[cfg(not(feature = "recv_cloexec"))]
+    #[test]
+    fn recv_fd_keeps_exec_without_feature() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_owned_fd().unwrap();
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, 0);
+    }
+
+    #[test]
+    fn recv_fd_raw_keeps_exec() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_fd_raw().unwrap();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, 0);
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn abstract_socket_works() {
+        let name = format!("passfd-test-{}", std::process::id());
+        let listener = bind_abstract(name.as_bytes()).unwrap();
+        let sender = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let file = File::open("/etc/passwd").unwrap();
+            stream.send_fd(file.as_raw_fd()).unwrap();
+        });
+        let stream = connect_abstract(name.as_bytes()).unwrap();
+        let mut file = stream.recv_file().unwrap();
+        sender.join().unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+
+    #[test]
+    fn datagram_send_fd_to_works() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let server_path = dir.join(format!("passfd-server-{}.sock", id));
+        let client_path = dir.join(format!("passfd-client-{}.sock", id));
+        let _ = std::fs::remove_file(&server_path);
+        let _ = std::fs::remove_file(&client_path);
+        let server = UnixDatagram::bind(&server_path).unwrap();
+        let client = UnixDatagram::bind(&client_path).unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+
+        client
+            .send_fd_to(file.as_raw_fd(), &server.local_addr().unwrap())
+            .unwrap();
+        let (fd, from) = server.recv_fd_from().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert_eq!(
+            from.as_pathname(),
+            client.local_addr().unwrap().as_pathname()
+        );
+
+        // reply to whoever sent it
+        server.send_fd_to(file.as_raw_fd(), &from).unwrap();
+        drop(client.recv_file().unwrap());
+        std::fs::remove_file(server_path).unwrap();
+        std::fs::remove_file(client_path).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn abstract_datagram_send_fd_to_works() {
+        let name = format!("passfd-dgram-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let server = UnixDatagram::bind_addr(&addr).unwrap();
+        let client = UnixDatagram::unbound().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        client.send_fd_to(file.as_raw_fd(), &addr).unwrap();
+        drop(server.recv_file().unwrap());
+    }
+
+    #[test]
+    fn recv_fd_from_unnamed_peer() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let (fd, from) = rx.recv_fd_from().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert!(from.is_unnamed());
+    }
+
+    #[test]
+    fn recv_fd_without_data() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_with_payload(file.as_raw_fd(), &[]).unwrap();
+        let mut buf = [0u8; 4];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(n, 0);
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn datagram_works() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_owned_fd().unwrap();
+        let mut file = File::from(fd);
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn seqpacket_pair() -> (OwnedFd, OwnedFd) {
+        let mut fds = [-1; 2];
+        let rv =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(rv, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    // Darwin has no SOCK_SEQPACKET for Unix sockets
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    #[test]
+    fn seqpacket_works() {
+        let (tx, rx) = seqpacket_pair();
+        let (tx, rx) = (tx.as_raw_fd(), rx.as_raw_fd());
+        let a = File::open("/etc/passwd").unwrap();
+        let b = File::open("/etc/hosts").unwrap();
+
+        tx.send_fd(a.as_raw_fd()).unwrap();
+        drop(rx.recv_owned_fd().unwrap());
+
+        // payload bigger than the buffer is cut off, fd still arrives
+        tx.send_fd_with_payload(a.as_raw_fd(), b"longer than buf")
+            .unwrap();
+        let mut buf = [0u8; 4];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"long");
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        // message boundary is preserved, next one is received intact
+        tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+            .unwrap();
+        let fds = rx.recv_fds(2).unwrap();
+        assert_eq!(fds.len(), 2);
+        for fd in fds {
+            drop(unsafe { File::from_raw_fd(fd) });
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    #[test]
+    fn seqpacket_message_works() {
+        let (tx, rx) = seqpacket_pair();
+        let (tx, rx) = (tx.as_raw_fd(), rx.as_raw_fd());
+        let file = File::open("/etc/passwd").unwrap();
+
+        tx.send_message(Some(file.as_raw_fd()), b"with fd").unwrap();
+        tx.send_message(None, b"plain").unwrap();
+        let (fd, body) = rx.recv_message().unwrap();
+        assert_eq!(body, b"with fd");
+        drop(unsafe { File::from_raw_fd(fd.unwrap()) });
+        assert_eq!(rx.recv_message().unwrap(), (None, b"plain".to_vec()));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn recv_fd_with_creds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let on: c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                rx.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const c_int as *const c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        assert_eq!(rv, 0);
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let (fd, creds) = rx.recv_fd_with_creds().unwrap();
+        assert_eq!(creds.pid, Some(std::process::id() as libc::pid_t));
+        assert_eq!(creds.uid, unsafe { libc::getuid() });
+        assert_eq!(creds.gid, unsafe { libc::getgid() });
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn recv_fd_authenticated_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let creds = UCred {
+            pid: None,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        tx.send_fd_authenticated(file.as_raw_fd(), creds).unwrap();
+        let (_fd, received) = rx.recv_fd_authenticated().unwrap();
+        assert_eq!(received.pid, Some(std::process::id() as libc::pid_t));
+        assert_eq!((received.uid, received.gid), (creds.uid, creds.gid));
+
+        // nothing vouched for this one
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let err = rx.recv_fd_authenticated().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::NoCredentials));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn send_fd_with_creds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let on: c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                rx.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const c_int as *const c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        assert_eq!(rv, 0);
+        let file = File::open("/etc/passwd").unwrap();
+        let creds = UCred {
+            pid: None,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        tx.send_fd_with_creds(file.as_raw_fd(), creds).unwrap();
+        let (fd, received) = rx.recv_fd_with_creds().unwrap();
+        assert_eq!(received.pid, Some(std::process::id() as libc::pid_t));
+        assert_eq!((received.uid, received.gid), (creds.uid, creds.gid));
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        // kernel refuses someone else's pid
+        let forged = UCred {
+            pid: Some(1),
+            ..creds
+        };
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(tx.send_fd_with_creds(file.as_raw_fd(), forged).is_err());
+        }
+    }
+
+    #[test]
+    fn peer_cred_works() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let creds = FdPassingExt::peer_cred(&a).unwrap();
+        assert_eq!(creds.uid, unsafe { libc::getuid() });
+        assert_eq!(creds.gid, unsafe { libc::getgid() });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(creds.pid, Some(std::process::id() as libc::pid_t));
+    }
+
+    #[test]
+    fn send_fd_blocking_once_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.set_nonblocking(true).unwrap();
+        // fill the socket so a non-blocking send can't go through
+        let chunk = [0u8; 4096];
+        let mut filled = 0;
+        loop {
+            match (&tx).write(&chunk) {
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let file = File::open("/etc/passwd").unwrap();
+        let err = tx.send_fd(file.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let reader = std::thread::spawn(move || {
+            let mut filler = vec![0u8; filled];
+            (&rx).read_exact(&mut filler).unwrap();
+            drop(unsafe { File::from_raw_fd(rx.recv_fd().unwrap()) });
+        });
+        tx.send_fd_blocking_once(file.as_raw_fd()).unwrap();
+        let fl = unsafe { libc::fcntl(tx.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(fl & libc::O_NONBLOCK, libc::O_NONBLOCK);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn recv_fd_at_least_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_fd_at_least(100).unwrap();
+        assert!(fd >= 100);
+        #[cfg(feature = "recv_cloexec")]
+        {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+        drop(unsafe { File::from_raw_fd(fd) });
+
+        // already high enough, kept as is
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_fd_at_least(0).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_typed_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let dir = File::open("/").unwrap();
+        let null = File::open("/dev/null").unwrap();
+        let mut pipe = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let pipe_r = unsafe { File::from_raw_fd(pipe[0]) };
+        let pipe_w = unsafe { File::from_raw_fd(pipe[1]) };
+        let expected = [
+            (file.as_raw_fd(), FileType::Regular),
+            (dir.as_raw_fd(), FileType::Directory),
+            (null.as_raw_fd(), FileType::CharDevice),
+            (pipe_r.as_raw_fd(), FileType::Fifo),
+            (tx.as_raw_fd(), FileType::Socket),
+        ];
+        for (fd, kind) in expected {
+            tx.send_fd(fd).unwrap();
+            let (_received, got) = rx.recv_fd_typed().unwrap();
+            assert_eq!(got, kind);
+        }
+        drop(pipe_w);
+    }
+
+    #[test]
+    fn partial_send_is_reported() {
+        let file = File::open("/etc/passwd").unwrap();
+        let fd = file.as_raw_fd();
+        let payload = vec![7u8; 1 << 20];
+        type Send<'a> = &'a dyn Fn(&UnixStream) -> Result<(), Error>;
+        let sends: [Send; 2] = [&|tx| tx.send_fd_with_payload(fd, &payload), &|tx| {
+            MessageBuilder::new().fds(&[fd]).payload(&payload).send(tx)
+        }];
+        for send in sends {
+            let (tx, rx) = UnixStream::pair().unwrap();
+            let size: c_int = 4096;
+            let rv = unsafe {
+                libc::setsockopt(
+                    tx.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_SNDBUF,
+                    &size as *const c_int as *const c_void,
+                    mem::size_of_val(&size) as libc::socklen_t,
+                )
+            };
+            assert_eq!(rv, 0);
+            tx.set_nonblocking(true).unwrap();
+
+            let err = send(&tx).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Other);
+            let sent = match PassFdError::from(err) {
+                PassFdError::PartialSend { sent } => sent,
+                err => panic!("{}", err),
+            };
+            assert!(sent > 0 && sent < payload.len());
+
+            // the fd arrived with the first bytes, even though the send failed
+            let mut buf = vec![0u8; sent];
+            let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+            drop(unsafe { File::from_raw_fd(fd) });
+            assert!(n > 0 && n <= sent);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn remaining_fd_capacity_works() {
+        let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        let remaining = remaining_fd_capacity().unwrap();
+        assert!(remaining > 0);
+        // stdio at least is open
+        assert!((remaining as u64) < limit.rlim_cur as u64 - 2);
+    }
+
+    #[test]
+    fn timestamped_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let before = Instant::now();
+        tx.send_fd_timestamped(file.as_raw_fd()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let (fd, elapsed) = rx.recv_fd_timestamped().unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        assert!(elapsed >= Duration::from_millis(20));
+        assert!(elapsed <= before.elapsed());
+
+        // plain send_fd has only 4 bytes of payload
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let err = rx.recv_fd_timestamped().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::ShortPayload));
+    }
+
+    #[test]
+    fn peek_fd_available_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        assert!(!rx.peek_fd_available().unwrap());
+        (&tx).write_all(b"plain").unwrap();
+        assert!(!rx.peek_fd_available().unwrap());
+        let mut plain = [0u8; 5];
+        (&rx).read_exact(&mut plain).unwrap();
+
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        // peeking doesn't take the message
+        assert!(rx.peek_fd_available().unwrap());
+        assert!(rx.peek_fd_available().unwrap());
+        drop(rx.recv_owned_fd().unwrap());
+        assert!(!rx.peek_fd_available().unwrap());
+
+        drop(tx);
+        let err = rx.peek_fd_available().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn dyn_fd_passing_works() {
+        let (stream_tx, stream_rx) = UnixStream::pair().unwrap();
+        let (dgram_tx, dgram_rx) = UnixDatagram::pair().unwrap();
+        let pairs: Vec<(Box<dyn FdPassingExt>, Box<dyn FdPassingExt>)> = vec![
+            (Box::new(stream_tx), Box::new(stream_rx)),
+            (Box::new(dgram_tx), Box::new(dgram_rx)),
+        ];
+        for (tx, rx) in pairs {
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd(file.as_raw_fd()).unwrap();
+            let mut received = File::from(rx.recv_owned_fd().unwrap());
+            let mut buf = String::new();
+            received.read_to_string(&mut buf).unwrap();
+            assert!(!buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn send_fd_checked_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        assert!(can_send(file.as_raw_fd()));
+        tx.send_fd_checked(file.as_raw_fd()).unwrap();
+        drop(unsafe { File::from_raw_fd(rx.recv_fd().unwrap()) });
+
+        // fds closed here could be reused by other tests, so use ones
+        // that can't be open
+        for fd in [-1, c_int::MAX] {
+            assert!(!can_send(fd));
+            let err = tx.send_fd_checked(fd).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert!(matches!(PassFdError::from(err), PassFdError::InvalidFd));
+        }
+    }
+
+    #[test]
+    fn send_borrowed_fd_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_borrowed_fd(&file).unwrap();
+        drop(rx.recv_owned_fd().unwrap());
+    }
+
+    #[test]
+    fn try_send_fd_with_payload_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let n = tx
+            .try_send_fd_with_payload(file.as_raw_fd(), b"hello")
+            .unwrap();
+        assert_eq!(n, 5);
+        drop(rx.recv_owned_fd().unwrap());
+    }
+
+    #[test]
+    fn send_fd_with_flags_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd_with_flags(file.as_raw_fd(), b"flags", libc::MSG_DONTWAIT)
+            .unwrap();
+        let mut buf = [0u8; 5];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"flags");
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn message_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+
+        tx.send_message(None, b"plain").unwrap();
+        tx.send_message(Some(file.as_raw_fd()), b"with fd").unwrap();
+        tx.send_message(None, b"").unwrap();
+        assert_eq!(rx.recv_message().unwrap(), (None, b"plain".to_vec()));
+        let (fd, body) = rx.recv_message().unwrap();
+        assert_eq!(body, b"with fd");
+        let mut file = unsafe { File::from_raw_fd(fd.unwrap()) };
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+        assert_eq!(rx.recv_message().unwrap(), (None, Vec::new()));
+
+        // a 4 GiB prefix is refused before anything is allocated
+        tx.send_fds(&[file.as_raw_fd()], &u32::MAX.to_ne_bytes())
+            .unwrap();
+        let err = rx.recv_message().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            PassFdError::from(err),
+            PassFdError::FrameTooLong { len } if len == u32::MAX as u64
+        ));
+
+        let too_long = vec![0u8; MAX_FRAME_LEN + 1];
+        let err = tx.send_message(None, &too_long).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // empty datagrams are taken off the queue, not mistaken for EOF
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        tx.send(b"").unwrap();
+        tx.send_fds(&[file.as_raw_fd()], b"").unwrap();
+        tx.send_message(None, b"next").unwrap();
+        for _ in 0..2 {
+            let err = rx.recv_message().unwrap_err();
+            assert!(matches!(PassFdError::from(err), PassFdError::ShortMessage));
+        }
+        assert_eq!(rx.recv_message().unwrap(), (None, b"next".to_vec()));
+
+        // only a hung up seqpacket peer is EOF
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        {
+            let (tx, rx) = seqpacket_pair();
+            tx.send_fds(&[file.as_raw_fd()], b"").unwrap();
+            let err = rx.recv_message().unwrap_err();
+            assert!(matches!(PassFdError::from(err), PassFdError::ShortMessage));
+            drop(tx);
+            let err = rx.recv_message().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn framed_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+
+        tx.send_fd_framed(Some(file.as_raw_fd()), b"with fd")
+            .unwrap();
+        tx.send_fd_framed(None, b"plain").unwrap();
+        let (fd, body) = rx.recv_fd_framed().unwrap();
+        assert_eq!(body, b"with fd");
+        drop(unsafe { File::from_raw_fd(fd.unwrap()) });
+        assert_eq!(rx.recv_fd_framed().unwrap(), (None, b"plain".to_vec()));
+
+        // prefix is a big-endian u64
+        tx.send_fd_framed(None, b"abc").unwrap();
+        let mut raw = [0u8; 11];
+        (&rx).read_exact(&mut raw).unwrap();
+        assert_eq!(raw, *b"\0\0\0\0\0\0\0\x03abc");
+
+        // a hostile prefix is refused before anything is allocated
+        let huge = u64::MAX.to_be_bytes();
+        tx.send_fds(&[file.as_raw_fd()], &huge).unwrap();
+        let err = rx.recv_fd_framed().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            PassFdError::from(err),
+            PassFdError::FrameTooLong { len: u64::MAX }
+        ));
+
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        tx.send_fd_framed(Some(file.as_raw_fd()), b"datagram")
+            .unwrap();
+        let (fd, body) = rx.recv_fd_framed().unwrap();
+        assert_eq!(body, b"datagram");
+        drop(unsafe { File::from_raw_fd(fd.unwrap()) });
+
+        // the refused datagram is dropped, the next one is fine
+        let too_long = (MAX_FRAME_LEN as u64 + 1).to_be_bytes();
+        tx.send_fds(&[file.as_raw_fd()], &too_long).unwrap();
+        tx.send_fd_framed(None, b"next").unwrap();
+        let err = rx.recv_fd_framed().unwrap_err();
+        assert!(matches!(
+            PassFdError::from(err),
+            PassFdError::FrameTooLong { .. }
+        ));
+        assert_eq!(rx.recv_fd_framed().unwrap(), (None, b"next".to_vec()));
+    }
+
+    #[test]
+    fn send_fd_with_ack_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let id = file_id(file.as_raw_fd());
+        let receiver = std::thread::spawn(move || {
+            let received = unsafe { File::from_raw_fd(rx.recv_fd_with_ack().unwrap()) };
+            assert_eq!(file_id(received.as_raw_fd()), id);
+            // wrong ack for the second one
+            drop(rx.recv_owned_fd().unwrap());
+            (&rx).write_all(b"?").unwrap();
+        });
+        tx.send_fd_with_ack(file.as_raw_fd()).unwrap();
+        let err = tx.send_fd_with_ack(file.as_raw_fd()).unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::BadAck));
+        receiver.join().unwrap();
+        // the peer is gone, so the send itself fails
+        match PassFdError::from(tx.send_fd_with_ack(file.as_raw_fd()).unwrap_err()) {
+            PassFdError::Io(err) => assert_eq!(err.kind(), ErrorKind::BrokenPipe),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn send_fd_all_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        // larger than the socket buffer, so it can't go in one sendmsg
+        let payload: Vec<u8> = (0..4 << 20).map(|i| i as u8).collect();
+        let expected = payload.clone();
+        let sender = std::thread::spawn(move || {
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd_all(file.as_raw_fd(), &payload).unwrap();
+        });
+        let mut buf = vec![0u8; expected.len()];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        (&rx).read_exact(&mut buf[n..]).unwrap();
+        sender.join().unwrap();
+        assert!(buf == expected);
+    }
+
+    #[test]
+    fn send_fd_vectored_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let bufs = [IoSlice::new(b"head"), IoSlice::new(b"body")];
+        tx.send_fd_vectored(file.as_raw_fd(), &bufs).unwrap();
+        let mut buf = [0u8; 8];
+        let (fd, n) = rx.recv_fd_with_payload(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"headbody");
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn send_fds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fds(&[file.as_raw_fd()], b"one").unwrap();
+        let fd = rx.recv_owned_fd().unwrap();
+        drop(fd);
+        let fds = [0, 1, 2];
+        tx.send_fds(&fds, b"stdio").unwrap();
+
+        // all three arrive in one SCM_RIGHTS header, with the payload
+        let mut payload = [0u8; 16];
+        let mut control = ControlBuf::for_fds(fds.len()).unwrap();
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr() as *mut c_void,
+            iov_len: payload.len(),
+        };
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+        let rv = unsafe { libc::recvmsg(rx.as_raw_fd(), &mut msg, 0) };
+        assert_eq!(&payload[..rv as usize], b"stdio");
+        assert_eq!(msg.msg_flags & libc::MSG_CTRUNC, 0);
+        let hdr = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        assert!(!hdr.is_null());
+        let expected = unsafe { libc::CMSG_LEN(mem::size_of_val(&fds) as u32) };
+        assert_eq!(unsafe { (*hdr).cmsg_len } as u32, expected);
+        let data = unsafe { libc::CMSG_DATA(hdr) } as *const c_int;
+        for i in 0..fds.len() {
+            drop(unsafe { OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))) });
+        }
+    }
+
+    #[test]
+    fn recv_fds_guarded_closes_untaken() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let a = File::open("/etc/passwd").unwrap();
+        let b = File::open("/etc/hosts").unwrap();
+        tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+            .unwrap();
+        let mut fds = rx.recv_fds_guarded(2).unwrap();
+        assert_eq!(fds.len(), 2);
+        let taken = fds.take(1).unwrap();
+        assert!(fds.take(1).is_none());
+        assert_eq!(fds.iter().count(), 1);
+        drop(fds);
+        let mut file = File::from(taken);
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert!(!buf.is_empty());
+    }
+
+    #[test]
+    fn recv_fds_truncated() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let fds = [0; 32];
+        tx.send_fds(&fds, b"many").unwrap();
+        let err = rx.recv_fds(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn recv_fd_closes_extra_fds() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        // padding leaves room for a second fd in a single-fd buffer
+        tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+            .unwrap();
+        drop(b);
+        let received = rx.recv_owned_fd();
+        // if b's duplicate leaked, a would never see EOF
+        drop(received);
+        let mut buf = [0u8; 1];
+        a.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!((&a).read(&mut buf).unwrap(), 0);
+    }
+
+    // Builds a message the way the kernel might report it: a single
+    // SCM_RIGHTS header with msg_controllen not including trailing padding
+    fn manual_msg(control: &mut ControlBuf, fds: &[RawFd]) -> msghdr {
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_RIGHTS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as u32) as _;
+            let data = libc::CMSG_DATA(hdr) as *mut c_int;
+            for (i, fd) in fds.iter().enumerate() {
+                std::ptr::write_unaligned(data.add(i), *fd);
+            }
+            msg.msg_controllen = (*hdr).cmsg_len as _;
+        }
+        msg
+    }
+
+    #[test]
+    fn single_fd_accepts_unpadded_controllen() {
+        let mut control = ControlBuf::single();
+        let fd = File::open("/etc/passwd").unwrap().into_raw_fd();
+        let msg = manual_msg(&mut control, &[fd]);
+        assert_ne!(msg.msg_controllen, unsafe {
+            libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as _
+        });
+        let fd = unsafe { single_fd(&msg) }.unwrap();
+        drop(File::from(fd));
+    }
+
+    #[test]
+    fn single_fd_rejects_empty_rights() {
+        let mut control = ControlBuf::single();
+        let msg = manual_msg(&mut control, &[]);
+        let err = unsafe { single_fd(&msg) }.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(PassFdError::from(err), PassFdError::NoFds));
+    }
+
+    #[test]
+    fn single_fd_rejects_short_header() {
+        let mut control = ControlBuf::single();
+        let mut msg = manual_msg(&mut control, &[0]);
+        // header claims part of an int only, nothing may be read from it
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_len = libc::CMSG_LEN(2) as _;
+            msg.msg_controllen = (*hdr).cmsg_len as _;
+        }
+        let err = unsafe { single_fd(&msg) }.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(PassFdError::from(err), PassFdError::BadCmsgLen));
+    }
+
+    #[test]
+    fn parse_control_works() {
+        // parsing owns nothing, so made up fds are fine
+        let mut control = ControlBuf::for_fds(2).unwrap();
+        let mut msg = manual_msg(&mut control, &[7, 8]);
+        let parsed = unsafe { parse_control(&msg) }.unwrap();
+        assert_eq!((parsed.fd_count, parsed.first_fd), (2, Some(7)));
+        assert!(!parsed.empty_rights && !parsed.other);
+
+        // header claims more than the buffer holds
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_len = libc::CMSG_LEN(64) as _;
+        }
+        let err = unsafe { parse_control(&msg) }.unwrap_err();
+        assert!(matches!(err, PassFdError::BadCmsgLen));
+
+        // header too short to cover itself ends the walk
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_len = 0;
+        }
+        let err = unsafe { parse_control(&msg) }.unwrap_err();
+        assert!(matches!(err, PassFdError::BadCmsgLen));
+
+        msg.msg_controllen = 0;
+        let parsed = unsafe { parse_control(&msg) }.unwrap();
+        assert_eq!(parsed, ParsedControl::default());
+    }
+
+    #[test]
+    fn recv_fds_into_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let a = File::open("/etc/passwd").unwrap();
+        let b = File::open("/etc/hosts").unwrap();
+        tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+            .unwrap();
+        let mut out = [-1; 4];
+        assert_eq!(rx.recv_fds_into(&mut out).unwrap(), 2);
+        assert_eq!(out[2..], [-1, -1]);
+        assert_eq!(file_id(out[0]), file_id(a.as_raw_fd()));
+        assert_eq!(file_id(out[1]), file_id(b.as_raw_fd()));
+        for fd in &out[..2] {
+            drop(unsafe { File::from_raw_fd(*fd) });
+        }
+
+        // the ones in the buffer's padding are closed, not returned
+        let fit = fds_fitting(1);
+        tx.send_fds(&vec![a.as_raw_fd(); fit], b"pad").unwrap();
+        let mut out = [-1; 1];
+        assert_eq!(rx.recv_fds_into(&mut out).unwrap(), 1);
+        assert_eq!(file_id(out[0]), file_id(a.as_raw_fd()));
+        drop(unsafe { File::from_raw_fd(out[0]) });
+
+        // the kernel drops the ones that don't fit at all
+        tx.send_fds(&vec![a.as_raw_fd(); fit + 1], b"many").unwrap();
+        match PassFdError::from(rx.recv_fds_into(&mut out).unwrap_err()) {
+            PassFdError::Truncated { delivered } => assert_eq!(delivered, fit),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn max_fds_per_message_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let many = vec![file.as_raw_fd(); MAX_FDS_PER_MESSAGE];
+        tx.send_fds(&many, b"many").unwrap();
+        let fds = rx.recv_fds_guarded(MAX_FDS_PER_MESSAGE).unwrap();
+        assert_eq!(fds.len(), MAX_FDS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn absurd_fd_counts_are_refused() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let file = File::open("/etc/passwd").unwrap();
+        let many = vec![file.as_raw_fd(); MAX_FDS_PER_MESSAGE + 1];
+        let err = tx.send_fds(&many, b"many").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("at most 253"));
+        let err = MessageBuilder::new()
+            .fds(&many)
+            .payload(b"many")
+            .send(&tx)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // would overflow CMSG_SPACE's u32 argument
+        let err = rx.recv_fds(usize::MAX / 4 + 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = ReceivedMessage::recv(&rx, usize::MAX, 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let mut out = [-1; MAX_FDS_PER_MESSAGE + 1];
+        let err = rx.recv_fds_into(&mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let capped = aligned_control_buffer(MAX_FDS_PER_MESSAGE).len();
+        assert_eq!(aligned_control_buffer(usize::MAX).len(), capped);
+
+        // nothing was sent
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        drop(unsafe { File::from_raw_fd(rx.recv_fd().unwrap()) });
+    }
+
+    #[test]
+    fn recv_fds_counted_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let a = File::open("/etc/passwd").unwrap();
+        tx.send_fds(&[a.as_raw_fd(), a.as_raw_fd()], b"two")
+            .unwrap();
+        let (fds, count) = rx.recv_fds_counted(2).unwrap();
+        assert_eq!((fds.len(), count), (2, 2));
+        for fd in fds {
+            assert_eq!(file_id(fd), file_id(a.as_raw_fd()));
+            drop(unsafe { File::from_raw_fd(fd) });
+        }
+
+        // fds in the padding of a buffer for one are counted, then closed
+        let fit = fds_fitting(1);
+        tx.send_fds(&vec![a.as_raw_fd(); fit], b"pad").unwrap();
+        let (fds, count) = rx.recv_fds_counted(1).unwrap();
+        assert_eq!((fds.len(), count), (1, fit));
+        assert_eq!(file_id(fds[0]), file_id(a.as_raw_fd()));
+        drop(unsafe { File::from_raw_fd(fds[0]) });
+
+        // too many for the padding: the kernel's count comes with the error
+        tx.send_fds(&vec![a.as_raw_fd(); fit + 1], b"many").unwrap();
+        match PassFdError::from(rx.recv_fds_counted(1).unwrap_err()) {
+            PassFdError::Truncated { delivered } => assert_eq!(delivered, fit),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn recv_fds_too_many() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        // every fd that fits the buffer's padding arrives, and is closed
+        let fit = fds_fitting(1);
+        tx.send_fds(&vec![0; fit.max(2)], b"two").unwrap();
+        let err = PassFdError::from(rx.recv_fds(1).unwrap_err());
+        if fit >= 2 {
+            assert!(matches!(err, PassFdError::TooManyFds), "{:?}", err);
+        } else {
+            assert!(
+                matches!(err, PassFdError::Truncated { delivered: 1 }),
+                "{:?}",
+                err
+            );
+        }
+
+        // more than that, and the kernel drops the rest
+        tx.send_fds(&vec![0; fit + 1], b"many").unwrap();
+        match PassFdError::from(rx.recv_fds(1).unwrap_err()) {
+            PassFdError::Truncated { delivered } => assert_eq!(delivered, fit),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn error_kind_is_recoverable() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tx.send_fds(&[], b"none").unwrap();
+        let err = rx.recv_fd().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            PassFdError::from(err),
+            PassFdError::NoControlMessage
+        ));
+
+        drop(tx);
+        let err = rx.recv_fd().unwrap_err();
+        assert!(matches!(PassFdError::from(err), PassFdError::UnexpectedEof));
+    }
+
+    #[test]
+    fn recv_fd_timeout_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let err = rx.recv_fd_timeout(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx.recv_fd_timeout(Duration::from_secs(1)).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_deadline_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let start = Instant::now();
+        let err = rx
+            .recv_fd_deadline(start + Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // already passed
+        let err = rx.recv_fd_deadline(start).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let file = File::open("/etc/passwd").unwrap();
+        tx.send_fd(file.as_raw_fd()).unwrap();
+        let fd = rx
+            .recv_fd_deadline(Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+
+    #[test]
+    fn recv_fd_retries_on_eintr() {
+        static CAUGHT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+        extern "C" fn handler(_: c_int) {
+            CAUGHT.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        // the handler is process-wide, the old one is put back below
+        let mut old: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe {
+            // no SA_RESTART, so the blocked recvmsg fails with EINTR
+            let mut sa: libc::sigaction = mem::zeroed();
+            sa.sa_sigaction = handler as extern "C" fn(c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR2, &sa, &mut old), 0);
+        }
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = unsafe { libc::pthread_self() };
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            unsafe { libc::pthread_kill(receiver, libc::SIGUSR2) };
+            std::thread::sleep(Duration::from_millis(50));
+            let file = File::open("/etc/passwd").unwrap();
+            tx.send_fd(file.as_raw_fd()).unwrap();
+        });
+        let fd = rx.recv_owned_fd();
+        sender.join().unwrap();
+        let rv = unsafe { libc::sigaction(libc::SIGUSR2, &old, std::ptr::null_mut()) };
+        assert_eq!(rv, 0);
+        let fd = fd.unwrap();
+        assert!(CAUGHT.load(std::sync::atomic::Ordering::SeqCst));
+        drop(fd);
+    }
+
+    #[test]
+    fn send_fd_to_closed_peer() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        drop(rx);
+        let file = File::open("/etc/passwd").unwrap();
+        let fd = file.as_raw_fd();
+        let sends: [&dyn Fn() -> Result<(), Error>; 6] = [
+            &|| tx.send_fd(fd),
+            &|| tx.send_fds(&[fd, fd], b"two"),
+            &|| tx.try_send_fd_with_payload(fd, b"x").map(|_| ()),
+            &|| tx.send_message(Some(fd), b"body"),
+            &|| MessageBuilder::new().fds(&[fd]).payload(b"x").send(&tx),
+            &|| FdChannel::new(tx.as_raw_fd()).send(fd),
+        ];
+        for send in sends {
+            let err = send().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+            assert_eq!(err.raw_os_error(), Some(libc::EPIPE));
+        }
+
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        {
+            let (tx, rx) = seqpacket_pair();
+            drop(rx);
+            let err = tx.send_fd(fd).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[test]
+    fn recv_fds_works() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let a = File::open("/etc/passwd").unwrap();
+        let b = File::open("/etc/hosts").unwrap();
+        tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+            .unwrap();
+        let fds = rx.recv_fds(4).unwrap();
+        assert_eq!(fds.len(), 2);
+        for fd in fds {
+            #[cfg(feature = "recv_cloexec")]
+            {
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+                assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
             }
+            drop(unsafe { File::from_raw_fd(fd) });
         }
     }
 }
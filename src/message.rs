@@ -0,0 +1,243 @@
+//! Building and receiving messages with any combination of fds,
+//! credentials and payload.
+
+use std::io::{Error, ErrorKind, IoSlice};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd, RawFd};
+
+use libc::{c_int, c_void, msghdr};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::UCred;
+use crate::{
+    check_bare_fds, check_truncated, is_eof, msg_fds, os_error, parse_control, raw, rights_space,
+    send_rest, set_cloexec, set_nosigpipe, ControlBuf, PassFdError, ReceivedFds, RECV_FLAGS,
+};
+
+/// Message assembled from parts, sent with a single `sendmsg`.
+///
+/// ```no_run
+/// use passfd::MessageBuilder;
+/// use std::os::unix::io::AsRawFd;
+/// use std::os::unix::net::UnixStream;
+///
+/// let (stream, _) = UnixStream::pair().unwrap();
+/// let file = std::fs::File::open("/etc/passwd").unwrap();
+/// MessageBuilder::new()
+///     .fds(&[file.as_raw_fd()])
+///     .payload(b"passwd")
+///     .send(&stream)
+///     .unwrap();
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessageBuilder<'a> {
+    fds: &'a [RawFd],
+    payload: &'a [u8],
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    creds: Option<UCred>,
+    flags: c_int,
+}
+
+impl<'a> MessageBuilder<'a> {
+    pub fn new() -> MessageBuilder<'a> {
+        MessageBuilder::default()
+    }
+
+    /// Descriptors to pass, all in one SCM_RIGHTS control message.
+    pub fn fds(mut self, fds: &'a [RawFd]) -> Self {
+        self.fds = fds;
+        self
+    }
+
+    /// Data portion of the message.
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Credentials to attach in a SCM_CREDENTIALS control message, see
+    /// `FdPassingExt::send_fd_with_creds`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn creds(mut self, creds: UCred) -> Self {
+        self.creds = Some(creds);
+        self
+    }
+
+    /// Flags passed through to `sendmsg`.
+    pub fn flags(mut self, flags: c_int) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Send the message. Control messages go with the first `sendmsg`, if
+    /// a stream socket takes only part of the payload the rest follows.
+    /// A non-blocking socket filling up after the fds went out is reported
+    /// as `PassFdError::PartialSend`, like `send_fds` does.
+    pub fn send<S: AsRawFd>(&self, socket: &S) -> Result<(), Error> {
+        let socket = socket.as_raw_fd();
+        let mut control_len = 0;
+        if !self.fds.is_empty() {
+            control_len += rights_space(self.fds.len())?;
+        }
+        // can't overflow, the count is checked above
+        let fds_len = mem::size_of_val(self.fds) as u32;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let creds = self.creds.map(|creds| libc::ucred {
+            pid: creds.pid.unwrap_or_else(|| unsafe { libc::getpid() }),
+            uid: creds.uid,
+            gid: creds.gid,
+        });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if creds.is_some() {
+            control_len +=
+                unsafe { libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as u32) as usize };
+        }
+
+        let mut control = ControlBuf::new(control_len);
+        let mut iov = libc::iovec {
+            iov_base: self.payload.as_ptr() as *mut c_void,
+            iov_len: self.payload.len(),
+        };
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if control_len > 0 {
+            msg.msg_control = control.as_mut_ptr();
+            msg.msg_controllen = control_len as _;
+        }
+        // headers follow each other, each padded to alignment (See cmsg(3))
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            if !self.fds.is_empty() {
+                (*hdr).cmsg_level = libc::SOL_SOCKET;
+                (*hdr).cmsg_type = libc::SCM_RIGHTS;
+                (*hdr).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+                let data = libc::CMSG_DATA(hdr) as *mut c_int;
+                for (i, fd) in self.fds.iter().enumerate() {
+                    std::ptr::write_unaligned(data.add(i), *fd);
+                }
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if let Some(ucred) = creds {
+                let hdr = if self.fds.is_empty() {
+                    hdr
+                } else {
+                    libc::CMSG_NXTHDR(&msg, hdr)
+                };
+                (*hdr).cmsg_level = libc::SOL_SOCKET;
+                (*hdr).cmsg_type = libc::SCM_CREDENTIALS;
+                (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of_val(&ucred) as u32) as _;
+                std::ptr::write_unaligned(libc::CMSG_DATA(hdr) as *mut libc::ucred, ucred);
+            }
+        }
+
+        set_nosigpipe(socket)?;
+        check_bare_fds(socket, self.fds, &[IoSlice::new(self.payload)])?;
+        let sent = unsafe { raw::sendmsg(socket, &msg, self.flags) }.map_err(os_error)?;
+        if sent == 0 && !self.payload.is_empty() {
+            return Err(Error::new(ErrorKind::WriteZero, "sendmsg wrote 0 bytes"));
+        }
+        send_rest(
+            socket,
+            None,
+            &mut [IoSlice::new(self.payload)],
+            sent,
+            !self.fds.is_empty(),
+            self.flags,
+        )
+    }
+}
+
+/// Everything a single `recvmsg` brought: fds, payload and credentials.
+#[derive(Debug)]
+pub struct ReceivedMessage {
+    fds: ReceivedFds,
+    payload: Vec<u8>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    creds: Option<UCred>,
+}
+
+impl ReceivedMessage {
+    /// Receive a message with up to `max_fds` descriptors and up to
+    /// `max_payload` bytes of data. `max_fds` can't be more than
+    /// `MAX_FDS_PER_MESSAGE`.
+    ///
+    /// A message without any descriptors is not an error. If more than
+    /// `max_fds` arrived, all of them are closed and an
+    /// `ErrorKind::InvalidData` error is returned. Credentials are only
+    /// there if `SO_PASSCRED` was enabled on the socket before the peer
+    /// sent.
+    pub fn recv<S: AsRawFd>(
+        socket: &S,
+        max_fds: usize,
+        max_payload: usize,
+    ) -> Result<ReceivedMessage, Error> {
+        let socket = socket.as_raw_fd();
+        #[allow(unused_mut)]
+        let mut control_len = rights_space(max_fds)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            control_len +=
+                unsafe { libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as u32) as usize };
+        }
+        let mut control = ControlBuf::new(control_len);
+        let mut payload = vec![0u8; max_payload];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr() as *mut c_void,
+            iov_len: payload.len(),
+        };
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = control.len() as _;
+
+        let rv = unsafe { raw::recvmsg(socket, &mut msg, RECV_FLAGS) }.map_err(os_error)?;
+        if is_eof(&msg, rv) {
+            return Err(PassFdError::UnexpectedEof.into());
+        }
+        unsafe { check_truncated(&msg)? };
+        payload.truncate(rv);
+
+        // all fds are owned first, so they're closed on error
+        let parsed = unsafe { parse_control(&msg) };
+        let fds: Vec<OwnedFd> = unsafe { msg_fds(&msg).collect() };
+        #[allow(unused_variables)]
+        let parsed = parsed?;
+        // control buffer may have room for more than asked because of
+        // padding; drop closes them all
+        if fds.len() > max_fds {
+            return Err(PassFdError::TooManyFds.into());
+        }
+        for fd in &fds {
+            set_cloexec(fd.as_fd())?;
+        }
+        Ok(ReceivedMessage {
+            fds: ReceivedFds::new(fds),
+            payload,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            creds: parsed.creds,
+        })
+    }
+
+    /// Descriptors that came with the message, closed on drop unless taken.
+    pub fn fds(&mut self) -> &mut ReceivedFds {
+        &mut self.fds
+    }
+
+    /// Take the descriptors, leaving the rest of the message behind.
+    pub fn into_fds(self) -> ReceivedFds {
+        self.fds
+    }
+
+    /// Data portion of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Credentials of the sender, if the kernel attached them.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn creds(&self) -> Option<UCred> {
+        self.creds
+    }
+}
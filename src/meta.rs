@@ -0,0 +1,47 @@
+//! Plain data sent alongside a descriptor, see `send_fd_with`.
+
+use std::mem;
+use std::ptr;
+use std::slice;
+
+/// Types that can be sent as raw bytes.
+///
+/// # Safety
+///
+/// The type must have no padding and no pointers, e.g. a `#[repr(C)]`
+/// struct made of integers with no gaps between the fields.
+pub unsafe trait AsBytes: Sized {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// Types that can be rebuilt from raw bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, so no `bool`,
+/// `char`, enums or references.
+pub unsafe trait FromBytes: Sized {
+    /// Read a value from the start of `bytes`, `None` if there aren't enough.
+    fn read_from(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < mem::size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+    }
+}
+
+macro_rules! impl_plain {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl AsBytes for $ty {}
+            unsafe impl FromBytes for $ty {}
+        )*
+    };
+}
+
+impl_plain!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: AsBytes, const N: usize> AsBytes for [T; N] {}
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
@@ -0,0 +1,429 @@
+//! Syscall core depending only on `core` and libc, usable without `std`.
+//!
+//! Errors are raw `errno` values. The `std` API is built on top of this and
+//! turns them into `std::io::Error`.
+
+use core::mem;
+use core::ptr;
+
+use libc::{c_int, c_void, cmsghdr, msghdr};
+
+/// Raw `errno` value
+pub type Errno = c_int;
+
+// Where available, ask the kernel to set FD_CLOEXEC atomically on receive,
+// otherwise there's a window where another thread can fork+exec and leak it.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) const RECV_FLAGS: c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) const RECV_FLAGS: c_int = 0;
+
+// Don't raise SIGPIPE when the peer is gone, report EPIPE instead
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) const SEND_FLAGS: c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) const SEND_FLAGS: c_int = 0;
+
+/// Current value of `errno`
+#[cfg(any(
+    target_os = "linux",
+    target_os = "emscripten",
+    target_os = "hurd",
+    target_os = "redox",
+    target_os = "android",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "solaris",
+    target_os = "haiku",
+    target_os = "aix",
+    target_os = "nto"
+))]
+pub fn errno() -> Errno {
+    unsafe {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "emscripten",
+            target_os = "hurd",
+            target_os = "redox",
+            target_os = "dragonfly"
+        ))]
+        let location = libc::__errno_location();
+        #[cfg(any(
+            target_os = "android",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "cygwin"
+        ))]
+        let location = libc::__errno();
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+        let location = libc::__error();
+        #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+        let location = libc::___errno();
+        #[cfg(target_os = "haiku")]
+        let location = libc::_errnop();
+        #[cfg(target_os = "aix")]
+        let location = libc::_Errno();
+        #[cfg(target_os = "nto")]
+        let location = libc::__get_errno_ptr();
+        *location
+    }
+}
+
+/// Current value of `errno`
+///
+/// libc has no errno accessor wired up here for this target, so std reads
+/// it instead. Without std the raw layer doesn't build on such targets.
+#[cfg(all(
+    feature = "std",
+    not(any(
+        target_os = "linux",
+        target_os = "emscripten",
+        target_os = "hurd",
+        target_os = "redox",
+        target_os = "android",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "cygwin",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "haiku",
+        target_os = "aix",
+        target_os = "nto"
+    ))
+))]
+pub fn errno() -> Errno {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Most fds a single message may carry, Linux's `SCM_MAX_FD`.
+///
+/// Other platforms have no fixed count, only a limit on the control
+/// buffer size; the same cap is kept there so code behaves alike
+/// everywhere. Larger counts are refused before any control buffer size is
+/// computed, so the `u32` that `CMSG_SPACE` takes can't overflow.
+pub const MAX_FDS: usize = 253;
+
+/// Bytes a control message carrying `count` fds takes, `None` above
+/// `MAX_FDS`.
+pub fn rights_space(count: usize) -> Option<usize> {
+    if count > MAX_FDS {
+        return None;
+    }
+    Some(unsafe { libc::CMSG_SPACE((mem::size_of::<c_int>() * count) as u32) as usize })
+}
+
+/// Number of headers making up a control buffer for `count` fds.
+///
+/// The buffer is made of headers so it's aligned, see cmsg(3). Above
+/// `MAX_FDS` this saturates, so no buffer is big enough and `send_fds`
+/// returns `EINVAL`.
+pub fn control_hdrs(count: usize) -> usize {
+    rights_space(count).map_or(usize::MAX, |len| len.div_ceil(mem::size_of::<cmsghdr>()))
+}
+
+// Headers of a message, as CMSG_FIRSTHDR/CMSG_NXTHDR walk them. A header
+// too short to cover itself ends the walk, some CMSG_NXTHDR
+// implementations would return it again forever.
+pub(crate) unsafe fn headers(msg: &msghdr) -> impl Iterator<Item = *const cmsghdr> + '_ {
+    let first = libc::CMSG_FIRSTHDR(msg) as *const cmsghdr;
+    core::iter::successors(Some(first).filter(|hdr| !hdr.is_null()), move |hdr| {
+        #[allow(clippy::unnecessary_cast)]
+        if ((**hdr).cmsg_len as usize) < libc::CMSG_LEN(0) as usize {
+            return None;
+        }
+        Some(libc::CMSG_NXTHDR(msg, *hdr) as *const cmsghdr).filter(|hdr| !hdr.is_null())
+    })
+}
+
+// Bytes between the header's data and the end of the control buffer
+pub(crate) unsafe fn data_room(msg: &msghdr, hdr: *const cmsghdr) -> usize {
+    // msg_controllen is not usize everywhere
+    #[allow(clippy::unnecessary_cast)]
+    let end = msg.msg_control as usize + msg.msg_controllen as usize;
+    end.saturating_sub(libc::CMSG_DATA(hdr) as usize)
+}
+
+// Number of whole descriptors a SCM_RIGHTS header carries, as far as they
+// are inside the buffer
+pub(crate) unsafe fn rights_count(msg: &msghdr, hdr: *const cmsghdr) -> usize {
+    // cmsg_len is not usize everywhere
+    #[allow(clippy::unnecessary_cast)]
+    let data_len = ((*hdr).cmsg_len as usize).saturating_sub(libc::CMSG_LEN(0) as usize);
+    data_len.min(data_room(msg, hdr)) / mem::size_of::<c_int>()
+}
+
+// Every descriptor of every SCM_RIGHTS header, as far as it's inside the
+// buffer. Nothing owns them yet.
+pub(crate) unsafe fn rights(msg: &msghdr) -> impl Iterator<Item = c_int> + '_ {
+    headers(msg)
+        .filter(|hdr| {
+            (**hdr).cmsg_level == libc::SOL_SOCKET && (**hdr).cmsg_type == libc::SCM_RIGHTS
+        })
+        .flat_map(move |hdr| {
+            let data = libc::CMSG_DATA(hdr) as *const c_int;
+            (0..rights_count(msg, hdr)).map(move |i| ptr::read_unaligned(data.add(i)))
+        })
+}
+
+/// `sendmsg`, restarted if interrupted by a signal. `MSG_NOSIGNAL` is
+/// added where supported.
+///
+/// # Safety
+///
+/// `msg` must point to valid buffers, as for `sendmsg(2)`.
+pub unsafe fn sendmsg(socket: c_int, msg: &msghdr, flags: c_int) -> Result<usize, Errno> {
+    let result = loop {
+        let rv = libc::sendmsg(socket, msg, flags | SEND_FLAGS);
+        if rv >= 0 {
+            break Ok(rv as usize);
+        }
+        let err = errno();
+        if err != libc::EINTR {
+            break Err(err);
+        }
+    };
+    #[cfg(feature = "tracing")]
+    trace_msg("sendmsg", socket, msg, &result);
+    result
+}
+
+/// `recvmsg`, restarted if interrupted by a signal.
+///
+/// # Safety
+///
+/// `msg` must point to valid buffers, as for `recvmsg(2)`.
+pub unsafe fn recvmsg(socket: c_int, msg: &mut msghdr, flags: c_int) -> Result<usize, Errno> {
+    let result = loop {
+        let rv = libc::recvmsg(socket, msg, flags);
+        if rv >= 0 {
+            break Ok(rv as usize);
+        }
+        let err = errno();
+        if err != libc::EINTR {
+            break Err(err);
+        }
+    };
+    #[cfg(feature = "tracing")]
+    trace_msg("recvmsg", socket, msg, &result);
+    result
+}
+
+// Emit an event describing a sendmsg/recvmsg call. Descriptors are counted
+// from the control buffer, which the kernel has filled in for recvmsg.
+#[cfg(feature = "tracing")]
+unsafe fn trace_msg(op: &'static str, socket: c_int, msg: &msghdr, result: &Result<usize, Errno>) {
+    let mut fds = 0;
+    if result.is_ok() && !msg.msg_control.is_null() {
+        fds = rights(msg).count();
+    }
+    match result {
+        Ok(bytes) => tracing::trace!(socket, fds, payload_len = *bytes, "{}", op),
+        Err(errno) => tracing::debug!(socket, errno = *errno, "{} failed", op),
+    }
+}
+
+/// Send `fds` with `payload` in a single message. `control` needs at least
+/// `control_hdrs(fds.len())` headers, otherwise `EINVAL` is returned.
+///
+/// Returns number of payload bytes sent.
+pub fn send_fds(
+    socket: c_int,
+    fds: &[c_int],
+    payload: &[u8],
+    control: &mut [cmsghdr],
+    flags: c_int,
+) -> Result<usize, Errno> {
+    if control.len() < control_hdrs(fds.len()) {
+        return Err(libc::EINVAL);
+    }
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut c_void,
+        iov_len: payload.len(),
+    };
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of_val(fds) as u32) } as _;
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            ptr::write_bytes(hdr as *mut u8, 0, msg.msg_controllen as usize);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_RIGHTS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as u32) as _;
+            let data = libc::CMSG_DATA(hdr) as *mut c_int;
+            for (i, fd) in fds.iter().enumerate() {
+                ptr::write_unaligned(data.add(i), *fd);
+            }
+        }
+    }
+    unsafe { sendmsg(socket, &msg, flags) }
+}
+
+/// Receive up to `fds.len()` fds into `fds`, and the data portion of the
+/// message into `payload`. `control` should have `control_hdrs(fds.len())`
+/// headers.
+///
+/// Returns number of payload bytes and number of fds received. Zero bytes
+/// on a stream socket means the peer closed the connection. If more fds
+/// arrived than fit, all of them are closed and `EMSGSIZE` is returned.
+/// Received fds have `FD_CLOEXEC` set.
+pub fn recv_fds(
+    socket: c_int,
+    payload: &mut [u8],
+    fds: &mut [c_int],
+    control: &mut [cmsghdr],
+) -> Result<(usize, usize), Errno> {
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut c_void,
+        iov_len: payload.len(),
+    };
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(control) as _;
+    let bytes = unsafe { recvmsg(socket, &mut msg, RECV_FLAGS)? };
+
+    let mut count = 0;
+    let mut overflow = msg.msg_flags & libc::MSG_CTRUNC != 0;
+    for fd in unsafe { rights(&msg) } {
+        if count < fds.len() {
+            fds[count] = fd;
+            count += 1;
+        } else {
+            unsafe { libc::close(fd) };
+            overflow = true;
+        }
+    }
+    if overflow {
+        for fd in &fds[..count] {
+            unsafe { libc::close(*fd) };
+        }
+        return Err(libc::EMSGSIZE);
+    }
+    if RECV_FLAGS == 0 {
+        for fd in &fds[..count] {
+            if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                // the caller never sees these fds, so they're closed here
+                let err = errno();
+                for fd in &fds[..count] {
+                    unsafe { libc::close(*fd) };
+                }
+                return Err(err);
+            }
+        }
+    }
+    #[cfg(feature = "leak_check")]
+    fds[..count].iter().for_each(|fd| crate::leaks::track(*fd));
+    Ok((bytes, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absurd_count_is_refused() {
+        assert_eq!(rights_space(MAX_FDS + 1), None);
+        assert_eq!(control_hdrs(usize::MAX), usize::MAX);
+        let fds = [0; MAX_FDS + 1];
+        let mut control = [unsafe { mem::zeroed::<cmsghdr>() }; 4];
+        let rv = send_fds(-1, &fds, b"", &mut control, 0);
+        assert_eq!(rv, Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn rights_stay_inside_the_buffer() {
+        // a header claiming 8 fds in a buffer with room for one or two
+        let mut control = [unsafe { mem::zeroed::<cmsghdr>() }; 4];
+        let len = rights_space(1).unwrap();
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = len as _;
+        let hdr = unsafe { &mut *libc::CMSG_FIRSTHDR(&msg) };
+        hdr.cmsg_level = libc::SOL_SOCKET;
+        hdr.cmsg_type = libc::SCM_RIGHTS;
+        hdr.cmsg_len = unsafe { libc::CMSG_LEN(8 * mem::size_of::<c_int>() as u32) } as _;
+        let room = (len - unsafe { libc::CMSG_LEN(0) } as usize) / mem::size_of::<c_int>();
+        assert_eq!(unsafe { rights(&msg) }.count(), room);
+
+        // too short to cover itself, ends the walk
+        hdr.cmsg_len = 1;
+        assert_eq!(unsafe { rights(&msg) }.count(), 0);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let mut pair = [-1; 2];
+        let rv =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, pair.as_mut_ptr()) };
+        assert_eq!(rv, 0);
+        let mut control = [unsafe { mem::zeroed::<cmsghdr>() }; 4];
+        assert!(control_hdrs(2) <= control.len());
+
+        assert_eq!(send_fds(pair[0], &[0, 1], b"hi", &mut control, 0), Ok(2));
+        let mut payload = [0u8; 2];
+        let mut fds = [-1; 2];
+        assert_eq!(
+            recv_fds(pair[1], &mut payload, &mut fds, &mut control),
+            Ok((2, 2))
+        );
+        assert_eq!(&payload, b"hi");
+
+        // doesn't fit, everything is closed
+        send_fds(pair[0], &[0, 1], b"hi", &mut control, 0).unwrap();
+        let mut one = [-1; 1];
+        assert_eq!(
+            recv_fds(pair[1], &mut payload, &mut one, &mut control),
+            Err(libc::EMSGSIZE)
+        );
+
+        assert_eq!(
+            send_fds(pair[0], &[0, 1], b"hi", &mut control[..0], 0),
+            Err(libc::EINVAL)
+        );
+        for fd in fds.iter().chain(&pair) {
+            unsafe { libc::close(*fd) };
+        }
+    }
+}
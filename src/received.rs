@@ -0,0 +1,72 @@
+//! Guard owning a batch of received descriptors.
+
+#[cfg(feature = "leak_check")]
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd};
+
+use crate::hand_over;
+
+/// Descriptors received in a single message.
+///
+/// Any descriptor not taken out by the time the guard is dropped is closed,
+/// so nothing leaks if processing bails out early or panics.
+#[derive(Debug)]
+pub struct ReceivedFds {
+    fds: Vec<Option<OwnedFd>>,
+}
+
+impl ReceivedFds {
+    pub(crate) fn new(fds: Vec<OwnedFd>) -> ReceivedFds {
+        // the guard owns them until they're taken out
+        #[cfg(feature = "leak_check")]
+        fds.iter()
+            .for_each(|fd| crate::leaks::untrack(fd.as_raw_fd()));
+        ReceivedFds {
+            fds: fds.into_iter().map(Some).collect(),
+        }
+    }
+
+    /// Number of descriptors received, including ones already taken.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Take ownership of the descriptor at `index`, leaving `None` behind.
+    pub fn take(&mut self, index: usize) -> Option<OwnedFd> {
+        self.fds
+            .get_mut(index)
+            .and_then(Option::take)
+            .map(hand_over)
+    }
+
+    /// Descriptors still held by the guard.
+    pub fn iter(&self) -> impl Iterator<Item = BorrowedFd<'_>> {
+        self.fds.iter().flatten().map(AsFd::as_fd)
+    }
+
+    /// Give up ownership of the descriptors still held, the caller becomes
+    /// responsible for closing them.
+    pub fn into_vec(mut self) -> Vec<RawFd> {
+        self.fds
+            .drain(..)
+            .flatten()
+            .map(|fd| hand_over(fd).into_raw_fd())
+            .collect()
+    }
+}
+
+impl IntoIterator for ReceivedFds {
+    type Item = OwnedFd;
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Option<OwnedFd>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        #[cfg(feature = "leak_check")]
+        self.iter()
+            .for_each(|fd| crate::leaks::track(fd.as_raw_fd()));
+        self.fds.into_iter().flatten()
+    }
+}
@@ -1,154 +1,543 @@
-//! Support for tokio 0.2 UnixStream.
-//! It does a really bad `mem::transmute`, which is *NOT SAFE*
+//! Support for tokio 1 UnixStream and UnixDatagram.
+//!
+//! The futures wait for readiness on the tokio socket, then make a
+//! non-blocking `sendmsg`/`recvmsg` on its fd inside `try_io`, so a
+//! spurious wakeup just clears the readiness and waits again.
 
 use std::future::Future;
-use std::io::{Error, ErrorKind};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Error, ErrorKind, IoSlice};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
 
-use futures_core::ready;
+use futures_core::{ready, Stream};
+use libc::c_int;
 
 use tokio::io::Interest;
-use tokio::net::UnixStream;
+use tokio::net::{UnixDatagram, UnixStream};
 
 use crate::FdPassingExt as SyncFdPassingExt;
+use crate::{send_with, ControlBuf};
 
-/// Main trait, extends UnixStream
+/// Main trait, extends UnixStream and UnixDatagram
 pub trait FdPassingExt {
+    /// Socket type the futures borrow
+    type Socket;
+
     /// Send RawFd. No type information is transmitted.
-    fn send_fd(&self, fd: RawFd) -> SendFd;
+    fn send_fd(&self, fd: RawFd) -> SendFd<'_, Self::Socket>;
+    /// Send RawFd with custom payload. The future owns the payload, so it
+    /// can outlive the buffer the caller built it from.
+    ///
+    /// On a stream socket the fd goes with the first part of the payload
+    /// and the rest follows as the socket becomes writable again.
+    fn send_fd_with_payload(
+        &self,
+        fd: RawFd,
+        payload: Vec<u8>,
+    ) -> SendFdWithPayload<'_, Self::Socket>;
     /// Receive RawFd. No type information is transmitted.
-    fn recv_fd(&self) -> RecvFd;
+    fn recv_fd(&self) -> RecvFd<'_, Self::Socket>;
+    /// Send several RawFds in a single message.
+    fn send_fds<'a>(&'a self, fds: &'a [RawFd]) -> SendFds<'a, Self::Socket>;
+    /// Receive up to `max` RawFds sent in a single message.
+    fn recv_fds(&self, max: usize) -> RecvFds<'_, Self::Socket>;
+    /// Stream of RawFds, one per message, as they arrive.
+    ///
+    /// Waits for readiness when nothing is queued. Ends when the peer
+    /// closes its write side; any other error is yielded once and ends it
+    /// too.
+    fn recv_fds_stream(&self) -> RecvFdStream<'_, Self::Socket>;
 }
 
-pub struct SendFd<'a> {
-    stream: &'a UnixStream,
+pub struct SendFd<'a, S = UnixStream> {
+    socket: &'a S,
     fd: RawFd,
 }
 
-impl<'a> Future for SendFd<'a> {
-    type Output = Result<(), Error>;
+pub struct SendFdWithPayload<'a, S = UnixStream> {
+    socket: &'a S,
+    // None once the fd went out
+    fd: Option<RawFd>,
+    payload: Vec<u8>,
+    sent: usize,
+}
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let this = Pin::into_inner(self);
-        let stream_fd = this.stream.as_raw_fd();
+pub struct RecvFd<'a, S = UnixStream> {
+    socket: &'a S,
+}
 
-        loop {
-            ready!(this.stream.poll_write_ready(cx))?;
+pub struct SendFds<'a, S = UnixStream> {
+    socket: &'a S,
+    // None once the fds went out
+    fds: Option<&'a [RawFd]>,
+    sent: usize,
+}
 
-            let res = this
-                .stream
-                .try_io(Interest::WRITABLE, || stream_fd.send_fd(this.fd));
-            match res {
-                Ok(_) => break Poll::Ready(Ok(())),
-                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-                Err(err) => break Poll::Ready(Err(err)),
-            }
-        }
-    }
+pub struct RecvFds<'a, S = UnixStream> {
+    socket: &'a S,
+    max: usize,
 }
 
-pub struct RecvFd<'a> {
-    stream: &'a UnixStream,
+pub struct RecvFdStream<'a, S = UnixStream> {
+    socket: &'a S,
+    done: bool,
 }
 
-impl<'a> Future for RecvFd<'a> {
-    type Output = Result<RawFd, Error>;
+// Stream and datagram sockets have similar readiness APIs, but no common trait
+macro_rules! impl_for_socket {
+    ($(($ty:ty, $poll_read:ident, $poll_write:ident)),*) => {$(
+        impl<'a> Future for SendFd<'a, $ty> {
+            type Output = Result<(), Error>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
+
+                loop {
+                    ready!(this.socket.$poll_write(cx))?;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let this = Pin::into_inner(self);
-        let stream_fd = this.stream.as_raw_fd();
+                    let res = this
+                        .socket
+                        .try_io(Interest::WRITABLE, || socket_fd.send_fd_nonblocking(this.fd));
+                    match res {
+                        Ok(_) => break Poll::Ready(Ok(())),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) => break Poll::Ready(Err(err)),
+                    }
+                }
+            }
+        }
 
-        loop {
-            ready!(this.stream.poll_read_ready(cx))?;
+        impl<'a> Future for SendFdWithPayload<'a, $ty> {
+            type Output = Result<(), Error>;
 
-            let res = this
-                .stream
-                .try_io(Interest::READABLE, || stream_fd.recv_fd());
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
 
+                while this.fd.is_some() || this.sent < this.payload.len() {
+                    ready!(this.socket.$poll_write(cx))?;
+
+                    let fds = this.fd.as_slice();
+                    let bufs = [IoSlice::new(&this.payload[this.sent..])];
+                    let res = this.socket.try_io(Interest::WRITABLE, || {
+                        let mut control = ControlBuf::single();
+                        send_with(socket_fd, None, fds, &bufs, &mut control, libc::MSG_DONTWAIT)
+                    });
+                    // writing nothing of a non-empty payload is an error, so
+                    // this makes progress
+                    match res {
+                        Ok(n) => {
+                            this.fd = None;
+                            this.sent += n;
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) => return Poll::Ready(Err(err)),
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        impl<'a> Future for RecvFd<'a, $ty> {
+            type Output = Result<RawFd, Error>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
+
+                loop {
+                    ready!(this.socket.$poll_read(cx))?;
+
+                    let res = this
+                        .socket
+                        .try_io(Interest::READABLE, || socket_fd.recv_fd_nonblocking());
+
+                    match res {
+                        Ok(val) => break Poll::Ready(Ok(val)),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) => break Poll::Ready(Err(err)),
+                    }
+                }
+            }
+        }
+
+        impl<'a> Future for SendFds<'a, $ty> {
+            type Output = Result<(), Error>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
+                let dummy_payload = [0u8; mem::size_of::<c_int>()];
+
+                while this.fds.is_some() || this.sent < dummy_payload.len() {
+                    ready!(this.socket.$poll_write(cx))?;
+
+                    let fds = this.fds.unwrap_or_default();
+                    let bufs = [IoSlice::new(&dummy_payload[this.sent..])];
+                    let res = this.socket.try_io(Interest::WRITABLE, || {
+                        let mut control = ControlBuf::for_fds(fds.len())?;
+                        send_with(
+                            socket_fd,
+                            None,
+                            fds,
+                            &bufs,
+                            &mut control,
+                            libc::MSG_DONTWAIT,
+                        )
+                    });
+                    match res {
+                        Ok(n) => {
+                            this.fds = None;
+                            this.sent += n;
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) => return Poll::Ready(Err(err)),
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        impl<'a> Future for RecvFds<'a, $ty> {
+            type Output = Result<Vec<RawFd>, Error>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
+
+                loop {
+                    ready!(this.socket.$poll_read(cx))?;
+
+                    let res = this
+                        .socket
+                        .try_io(Interest::READABLE, || socket_fd.recv_fds(this.max));
+
+                    match res {
+                        Ok(val) => break Poll::Ready(Ok(val)),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) => break Poll::Ready(Err(err)),
+                    }
+                }
+            }
+        }
+
+        impl<'a> Stream for RecvFdStream<'a, $ty> {
+            type Item = Result<RawFd, Error>;
+
+            fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+                let this = Pin::into_inner(self);
+                let socket_fd = this.socket.as_raw_fd();
+
+                while !this.done {
+                    if let Err(err) = ready!(this.socket.$poll_read(cx)) {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(err)));
+                    }
+
+                    let res = this
+                        .socket
+                        .try_io(Interest::READABLE, || socket_fd.recv_fd_nonblocking());
+
+                    match res {
+                        Ok(val) => return Poll::Ready(Some(Ok(val))),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => this.done = true,
+                        Err(err) => {
+                            this.done = true;
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                }
+                Poll::Ready(None)
+            }
+        }
+
+        impl FdPassingExt for $ty {
+            type Socket = $ty;
+
+            fn send_fd(&self, fd: RawFd) -> SendFd<'_, $ty> {
+                SendFd { socket: self, fd }
+            }
+
+            fn send_fd_with_payload(&self, fd: RawFd, payload: Vec<u8>) -> SendFdWithPayload<'_, $ty> {
+                SendFdWithPayload {
+                    socket: self,
+                    fd: Some(fd),
+                    payload,
+                    sent: 0,
+                }
+            }
+
+            fn recv_fd(&self) -> RecvFd<'_, $ty> {
+                RecvFd { socket: self }
+            }
+
+            fn send_fds<'a>(&'a self, fds: &'a [RawFd]) -> SendFds<'a, $ty> {
+                SendFds {
+                    socket: self,
+                    fds: Some(fds),
+                    sent: 0,
+                }
+            }
+
+            fn recv_fds(&self, max: usize) -> RecvFds<'_, $ty> {
+                RecvFds { socket: self, max }
+            }
+
+            fn recv_fds_stream(&self) -> RecvFdStream<'_, $ty> {
+                RecvFdStream {
+                    socket: self,
+                    done: false,
+                }
+            }
+        }
+    )*};
+}
+
+impl_for_socket!(
+    (UnixStream, poll_read_ready, poll_write_ready),
+    (UnixDatagram, poll_recv_ready, poll_send_ready)
+);
+
+/// Control socket handing out received descriptors as `OwnedFd`s, e.g. to
+/// spawn a worker task for each.
+///
+/// Unlike `recv_fds_stream`, it owns the socket, so it can be moved into a
+/// task of its own.
+pub struct FdReceiver {
+    socket: UnixStream,
+    done: bool,
+}
+
+impl FdReceiver {
+    pub fn new(socket: UnixStream) -> FdReceiver {
+        FdReceiver {
+            socket,
+            done: false,
+        }
+    }
+
+    /// Next descriptor, owned by the caller and closed on drop.
+    ///
+    /// `None` once the peer closed its write side. Any other error is
+    /// returned once, after which this returns `None` too.
+    pub async fn next_fd(&mut self) -> Option<Result<OwnedFd, Error>> {
+        let socket_fd = self.socket.as_raw_fd();
+        while !self.done {
+            if let Err(err) = self.socket.readable().await {
+                self.done = true;
+                return Some(Err(err));
+            }
+            let res = self
+                .socket
+                .try_io(Interest::READABLE, || socket_fd.recv_fd_nonblocking());
             match res {
-                Ok(val) => break Poll::Ready(Ok(val)),
+                Ok(fd) => return Some(Ok(unsafe { OwnedFd::from_raw_fd(fd) })),
                 Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-                Err(err) => break Poll::Ready(Err(err)),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
             }
         }
+        None
     }
-}
 
-impl FdPassingExt for UnixStream {
-    fn send_fd(&self, fd: RawFd) -> SendFd {
-        SendFd { stream: self, fd }
+    pub fn get_ref(&self) -> &UnixStream {
+        &self.socket
     }
 
-    fn recv_fd(&self) -> RecvFd {
-        RecvFd { stream: self }
+    pub fn into_inner(self) -> UnixStream {
+        self.socket
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FdPassingExt;
+    use super::{FdPassingExt, FdReceiver};
+    use crate::FdPassingExt as SyncFdPassingExt;
+    use futures_core::Stream;
     use std::fs::File;
     use std::io::Read;
     use std::os::fd::{AsRawFd, FromRawFd};
-    use tempdir::TempDir;
+    use std::pin::Pin;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::{UnixListener, UnixStream};
+    use tokio::net::{UnixDatagram, UnixStream};
     use tokio::runtime::Builder;
 
     #[test]
     fn async_it_works() {
-        let tmp_dir = TempDir::new("passfd").unwrap();
-        let sock_path = tmp_dir.path().join("listener.sock");
+        let (parent, child) = std::os::unix::net::UnixStream::pair().unwrap();
+        parent.set_nonblocking(true).unwrap();
+        child.set_nonblocking(true).unwrap();
 
         match unsafe { libc::fork() } {
             -1 => panic!("fork went wrong"),
             0 => {
-                println!("child process, wait for socket to appear");
+                drop(parent);
                 let rt = Builder::new_current_thread().enable_all().build().unwrap();
-                rt.block_on(async {
-                    for _ in 0..10 {
-                        match UnixStream::connect(sock_path.clone()).await {
-                            Ok(mut stream) => {
-                                println!("stream connected");
-                                let fd = stream.recv_fd().await?;
-                                println!("fd received");
-                                let mut file = unsafe { File::from_raw_fd(fd) };
-                                let mut buf = String::new();
-                                file.read_to_string(&mut buf)?;
-                                stream.write_all("ok".as_bytes()).await?;
-                                break;
-                            }
-                            Err(e) => {
-                                println!("not connected {:?}", e);
-                                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                            }
-                        }
-                    }
+                let res = rt.block_on(async {
+                    let mut stream = UnixStream::from_std(child)?;
+                    let fd = stream.recv_fd().await?;
+                    let mut file = unsafe { File::from_raw_fd(fd) };
+                    let mut buf = String::new();
+                    file.read_to_string(&mut buf)?;
+                    stream.write_all("ok".as_bytes()).await?;
                     Ok::<_, std::io::Error>(())
-                })
-                .unwrap();
+                });
+                // don't return into the test harness
+                unsafe { libc::_exit(if res.is_ok() { 0 } else { 1 }) };
             }
-            _ => {
-                println!("parent, start listening");
+            pid => {
+                drop(child);
                 let rt = Builder::new_current_thread().enable_all().build().unwrap();
                 rt.block_on(async {
-                    let listener = UnixListener::bind(sock_path)?;
-                    println!("opening file");
                     let file = File::open("/etc/passwd")?;
-                    let (mut stream, _) = listener.accept().await?;
-                    println!("client connected, sending fd");
+                    let mut stream = UnixStream::from_std(parent)?;
                     stream.send_fd(file.as_raw_fd()).await?;
                     let mut buf = String::new();
                     stream.read_to_string(&mut buf).await?;
-                    println!("got response {:?}", buf);
                     assert!(buf == "ok");
                     Ok::<_, std::io::Error>(())
                 })
                 .unwrap();
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
             }
         }
     }
+
+    #[test]
+    fn async_datagram_works() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let (tx, rx) = UnixDatagram::pair()?;
+            // receiver starts first, so it has to wait for readiness
+            let receiver = tokio::spawn(async move { rx.recv_fd().await });
+            tokio::task::yield_now().await;
+            let file = File::open("/etc/passwd")?;
+            tx.send_fd(file.as_raw_fd()).await?;
+            let fd = receiver.await.unwrap()?;
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            let mut buf = String::new();
+            file.read_to_string(&mut buf)?;
+            assert!(!buf.is_empty());
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn async_send_recv_fds() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let (tx, rx) = UnixStream::pair()?;
+            let a = File::open("/etc/passwd")?;
+            let b = File::open("/etc/hosts")?;
+            tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()]).await?;
+            let fds = rx.recv_fds(2).await?;
+            assert_eq!(fds.len(), 2);
+            for fd in fds {
+                drop(unsafe { File::from_raw_fd(fd) });
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn async_send_fd_with_payload() {
+        let (tx, rx) = std::os::unix::net::UnixStream::pair().unwrap();
+        tx.set_nonblocking(true).unwrap();
+        // more than fits the socket, so the rest follows the fd
+        let payload: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let expected = payload.clone();
+        let receiver = std::thread::spawn(move || {
+            let mut buf = vec![0u8; expected.len()];
+            let (fd, n) = SyncFdPassingExt::recv_fd_with_payload(&rx, &mut buf).unwrap();
+            drop(unsafe { File::from_raw_fd(fd) });
+            (&rx).read_exact(&mut buf[n..]).unwrap();
+            assert!(buf == expected);
+        });
+
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let tx = UnixStream::from_std(tx)?;
+            let file = File::open("/etc/passwd")?;
+            let future = tx.send_fd_with_payload(file.as_raw_fd(), payload);
+            // the caller's buffer is gone, the future still owns a copy
+            future.await?;
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn async_fd_receiver() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let (tx, rx) = UnixStream::pair()?;
+            let mut receiver = FdReceiver::new(rx);
+            let sender = tokio::spawn(async move {
+                // separate opens, so each worker reads from the start
+                for _ in 0..3 {
+                    let file = File::open("/etc/passwd")?;
+                    tx.send_fd(file.as_raw_fd()).await?;
+                }
+                Ok::<_, std::io::Error>(())
+            });
+            let mut workers = Vec::new();
+            while let Some(fd) = receiver.next_fd().await {
+                let fd = fd?;
+                workers.push(tokio::spawn(async move {
+                    let mut buf = String::new();
+                    File::from(fd).read_to_string(&mut buf).map(|_| buf.len())
+                }));
+            }
+            assert_eq!(workers.len(), 3);
+            for worker in workers {
+                assert!(worker.await.unwrap()? > 0);
+            }
+            assert!(receiver.next_fd().await.is_none());
+            sender.await.unwrap()?;
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn async_recv_fds_stream() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let (tx, rx) = UnixStream::pair()?;
+            let mut stream = rx.recv_fds_stream();
+            let sender = tokio::spawn(async move {
+                let file = File::open("/etc/passwd")?;
+                for _ in 0..3 {
+                    tx.send_fd(file.as_raw_fd()).await?;
+                    tokio::task::yield_now().await;
+                }
+                Ok::<_, std::io::Error>(())
+            });
+            let mut count = 0;
+            while let Some(fd) =
+                std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+            {
+                drop(unsafe { File::from_raw_fd(fd?) });
+                count += 1;
+            }
+            assert_eq!(count, 3);
+            sender.await.unwrap()?;
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+    }
 }
@@ -0,0 +1,65 @@
+//! Passing a descriptor between unrelated processes. The test binary
+//! re-executes itself to run the sender, so the child shares nothing with
+//! the parent but the socket path.
+
+#![cfg(feature = "std")]
+
+use passfd::FdPassingExt;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::Command;
+
+const SOCKET_VAR: &str = "PASSFD_TEST_SOCKET";
+const FILE_VAR: &str = "PASSFD_TEST_FILE";
+
+// Runs as a no-op unless spawned by `fd_crosses_process_boundary`
+#[test]
+fn child_sender() {
+    let (Ok(socket), Ok(path)) = (env::var(SOCKET_VAR), env::var(FILE_VAR)) else {
+        return;
+    };
+    let file = File::open(path).unwrap();
+    let stream = UnixStream::connect(socket).unwrap();
+    stream.send_fd(file.as_raw_fd()).unwrap();
+    // wait for the parent to be done with the fd
+    let mut buf = [0u8; 2];
+    (&stream).read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ok");
+}
+
+#[test]
+fn fd_crosses_process_boundary() {
+    let dir = env::temp_dir().join(format!("passfd-xproc-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("sock");
+    let path = dir.join("file");
+    fs::write(&path, b"from another process").unwrap();
+    let listener = UnixListener::bind(&socket).unwrap();
+
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "child_sender", "--nocapture", "--test-threads=1"])
+        .env(SOCKET_VAR, &socket)
+        .env(FILE_VAR, &path)
+        .spawn()
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    // the child opened the file before connecting, only its fd is left now
+    fs::remove_dir_all(&dir).unwrap();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let creds = FdPassingExt::peer_cred(&stream).unwrap();
+        assert_eq!(creds.pid, Some(child.id() as libc::pid_t));
+    }
+    let fd = stream.recv_fd().unwrap();
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "from another process");
+    (&stream).write_all(b"ok").unwrap();
+
+    assert!(child.wait().unwrap().success());
+}
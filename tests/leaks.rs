@@ -0,0 +1,73 @@
+//! Received descriptors left open are reported by `check_leaks`. On its
+//! own, so no other test opens or closes fds meanwhile.
+
+#![cfg(all(feature = "leak_check", debug_assertions))]
+
+use passfd::{check_leaks, raw, FdPassingExt};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+
+#[test]
+fn leaks_are_reported() {
+    let (tx, rx) = UnixStream::pair().unwrap();
+    let file = File::open("/etc/passwd").unwrap();
+
+    tx.send_fd(file.as_raw_fd()).unwrap();
+    drop(rx.recv_owned_fd().unwrap());
+    tx.send_fd(file.as_raw_fd()).unwrap();
+    let leaked = rx.recv_fd().unwrap();
+    tx.send_fds(&[file.as_raw_fd(), file.as_raw_fd()], b"two")
+        .unwrap();
+    let batch = rx.recv_fds(2).unwrap();
+
+    let mut expected = vec![leaked, batch[0], batch[1]];
+    expected.sort();
+    assert_eq!(check_leaks(), expected);
+
+    for fd in batch.into_iter().chain(Some(leaked)) {
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+    assert!(check_leaks().is_empty());
+
+    // fds handed over after a dup are tracked, the closed originals are
+    // not, even once their numbers are reused
+    tx.send_fd(file.as_raw_fd()).unwrap();
+    let high = rx.recv_fd_at_least(100).unwrap();
+    let reused = File::open("/etc/passwd").unwrap();
+    assert_eq!(check_leaks(), vec![high]);
+    drop(unsafe { File::from_raw_fd(high) });
+
+    let target = File::open("/dev/null").unwrap().into_raw_fd();
+    tx.send_fd(file.as_raw_fd()).unwrap();
+    rx.recv_fd_into(target).unwrap();
+    let reused_too = File::open("/etc/passwd").unwrap();
+    assert_eq!(check_leaks(), vec![target]);
+    drop(unsafe { File::from_raw_fd(target) });
+    drop((reused, reused_too));
+
+    // and so are the ones from the raw layer
+    tx.send_fd(file.as_raw_fd()).unwrap();
+    let mut payload = [0u8; 4];
+    let mut fds = [-1; 1];
+    let mut control = vec![unsafe { std::mem::zeroed() }; raw::control_hdrs(1)];
+    let (_, count) = raw::recv_fds(rx.as_raw_fd(), &mut payload, &mut fds, &mut control).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(check_leaks(), vec![fds[0]]);
+    drop(unsafe { File::from_raw_fd(fds[0]) });
+    assert!(check_leaks().is_empty());
+
+    // fds the crate closes itself aren't recorded: the extra one recv_fd
+    // drops, and both of a batch recv_fds refuses
+    tx.send_fds(&[file.as_raw_fd(), file.as_raw_fd()], b"two")
+        .unwrap();
+    let fd = rx.recv_fd().unwrap();
+    tx.send_fds(&[file.as_raw_fd(), file.as_raw_fd()], b"two")
+        .unwrap();
+    assert!(rx.recv_fds(1).is_err());
+    let reopened: Vec<File> = (0..3).map(|_| File::open("/etc/passwd").unwrap()).collect();
+    assert_eq!(check_leaks(), vec![fd]);
+    drop(unsafe { File::from_raw_fd(fd) });
+    drop(reopened);
+    assert!(check_leaks().is_empty());
+}
@@ -0,0 +1,71 @@
+//! A socket keeps the network namespace it was created in, whichever
+//! namespace the process receiving it lives in.
+
+#![cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+
+use passfd::FdPassingExt;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+// Child exit code when namespaces aren't available, e.g. without
+// CAP_SYS_ADMIN
+const SKIP: i32 = 77;
+
+fn child(socket: UnixStream, target: std::net::SocketAddr) -> i32 {
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        return SKIP;
+    }
+    // loopback is down in a fresh namespace, so its own sockets can't
+    // reach the target
+    match UdpSocket::bind("0.0.0.0:0").map(|fresh| fresh.send_to(b"lost", target)) {
+        Ok(Err(_)) => {}
+        _ => return 1,
+    }
+    let passed = match socket.recv_owned_fd() {
+        Ok(fd) => UdpSocket::from(fd),
+        Err(_) => return 2,
+    };
+    match passed.send_to(b"from netns", target) {
+        Ok(_) => 0,
+        Err(_) => 3,
+    }
+}
+
+#[test]
+fn socket_keeps_its_namespace() {
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let passed = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (parent, socket) = UnixStream::pair().unwrap();
+
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork went wrong"),
+        0 => {
+            drop(parent);
+            let code = child(socket, target.local_addr().unwrap());
+            // don't return into the test harness
+            unsafe { libc::_exit(code) };
+        }
+        pid => {
+            drop(socket);
+            parent.send_fd(passed.as_raw_fd()).unwrap();
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status));
+            if libc::WEXITSTATUS(status) == SKIP {
+                eprintln!("skipped: can't create a network namespace");
+                return;
+            }
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+
+            let mut buf = [0u8; 16];
+            let (n, from) = target.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"from netns");
+            assert_eq!(from, passed.local_addr().unwrap());
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Peeking at a message carrying fds must not put any fd in the process,
+//! on its own so nothing else opens or closes fds meanwhile.
+
+#![cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+
+use passfd::FdPassingExt;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+fn open_fds() -> usize {
+    // minus the directory being read
+    std::fs::read_dir("/proc/self/fd").unwrap().count() - 1
+}
+
+#[test]
+fn peek_installs_no_fds() {
+    let (tx, rx) = UnixStream::pair().unwrap();
+    let a = File::open("/etc/passwd").unwrap();
+    let b = File::open("/etc/hosts").unwrap();
+    tx.send_fds(&[a.as_raw_fd(), b.as_raw_fd()], b"two")
+        .unwrap();
+
+    let before = open_fds();
+    for _ in 0..10 {
+        assert!(rx.peek_fd_available().unwrap());
+    }
+    assert_eq!(open_fds(), before);
+
+    let fds = rx.recv_fds(2).unwrap();
+    assert_eq!(fds.len(), 2);
+    assert_eq!(open_fds(), before + 2);
+}